@@ -0,0 +1,89 @@
+use std::io::IsTerminal;
+use std::path::Path;
+use base64::Engine;
+use crate::{Content, FileData, ImageUrl};
+
+/// Attaches an image file, or the image piped to stdin when `path` is `-`.
+pub fn image(path: &str) -> Result<Content, String> {
+    if path == "-" {
+        return stdin_image();
+    }
+
+    let mime_type = match extension(path).as_str() {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        _ => return Err(format!("'{}' is not a supported image type (png, jpg, gif, or webp)", path)),
+    };
+
+    Ok(Content::ImageUrl {
+        image_url: ImageUrl { url: data_url(path, mime_type)? },
+    })
+}
+
+fn stdin_image() -> Result<Content, String> {
+    let stdin = std::io::stdin();
+    if stdin.is_terminal() {
+        return Err("--image - reads the image from stdin, but nothing was piped in".to_string());
+    }
+    let bytes = crate::read_input_stream(stdin).map_err(|e| format!("could not read the image from stdin: {}", e))?;
+    let mime_type = sniff_image_type(&bytes).ok_or("the data on stdin is not a supported image type (png, jpg, gif, or webp)")?;
+
+    Ok(Content::ImageUrl {
+        image_url: ImageUrl { url: format!("data:{};base64,{}", mime_type, base64::engine::general_purpose::STANDARD.encode(bytes)) },
+    })
+}
+
+/// Identifies an image from the signature at the start of its data.
+fn sniff_image_type(bytes: &[u8]) -> Option<&'static str> {
+    if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("image/png")
+    } else if bytes.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        Some("image/jpeg")
+    } else if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+        Some("image/gif")
+    } else if bytes.len() >= 12 && &bytes[..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
+        Some("image/webp")
+    } else {
+        None
+    }
+}
+
+pub fn file(path: &str) -> Result<Content, String> {
+    let mime_type = match extension(path).as_str() {
+        "pdf" => "application/pdf",
+        "txt" => "text/plain",
+        "md" => "text/markdown",
+        "csv" => "text/csv",
+        "json" => "application/json",
+        "html" | "htm" => "text/html",
+        "xml" => "application/xml",
+        "doc" => "application/msword",
+        "docx" => "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+        "xlsx" => "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+        "pptx" => "application/vnd.openxmlformats-officedocument.presentationml.presentation",
+        _ => "application/octet-stream",
+    };
+
+    let filename = Path::new(path)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| path.to_string());
+
+    Ok(Content::File {
+        file: FileData { filename, file_data: data_url(path, mime_type)? },
+    })
+}
+
+fn extension(path: &str) -> String {
+    Path::new(path)
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+        .unwrap_or_default()
+}
+
+fn data_url(path: &str, mime_type: &str) -> Result<String, String> {
+    let bytes = crate::read_input(path).map_err(|e| format!("could not read '{}': {}", path, e))?;
+    Ok(format!("data:{};base64,{}", mime_type, base64::engine::general_purpose::STANDARD.encode(bytes)))
+}
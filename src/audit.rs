@@ -0,0 +1,27 @@
+use std::collections::HashMap;
+use std::io::Write;
+use serde::Serialize;
+use serde_json::Value;
+
+/// One line of the audit log.
+#[derive(Serialize)]
+pub struct Entry<'a> {
+    pub timestamp: String,
+    pub endpoint: &'a str,
+    pub deployment: &'a str,
+    pub status: u16,
+    pub duration_ms: u128,
+    pub usage: &'a Value,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub tags: &'a HashMap<String, String>,
+}
+
+pub fn append(path: &str, entry: &Entry) -> Result<(), String> {
+    let line = serde_json::to_string(entry).map_err(|e| e.to_string())?;
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| format!("could not open audit log '{}': {}", path, e))?;
+    writeln!(file, "{}", line).map_err(|e| format!("could not write to audit log '{}': {}", path, e))
+}
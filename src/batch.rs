@@ -0,0 +1,228 @@
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use futures::stream::{self, StreamExt};
+use log::debug;
+use serde_json::{json, Value};
+use crate::{Api, ChatRequest, Content};
+
+/// One input file of a --batch run.
+pub struct Item {
+    pub name: String,
+    pub input: String,
+}
+
+enum Confirmation {
+    Send,
+    Skip,
+    Stop,
+}
+
+/// Shows an input on stderr and asks whether to send it.
+fn confirm(item: &Item) -> Result<Confirmation, Box<dyn std::error::Error>> {
+    crate::diagnostics::status("confirm_input", format!("===== {} =====\n{}", item.name, item.input.trim_end()));
+    loop {
+        eprint!("Send this input? [y]es, [s]kip, [n]o and stop: ");
+        std::io::stderr().flush()?;
+        let mut answer = String::new();
+        if std::io::stdin().read_line(&mut answer)? == 0 {
+            return Ok(Confirmation::Stop);
+        }
+        match answer.trim().to_lowercase().as_str() {
+            "y" | "yes" => return Ok(Confirmation::Send),
+            "s" | "skip" => return Ok(Confirmation::Skip),
+            "n" | "no" | "q" | "quit" => return Ok(Confirmation::Stop),
+            _ => {}
+        }
+    }
+}
+
+/// What became of one batch input.
+enum Outcome {
+    Sent(Result<Value, String>),
+    /// The input is identical to the earlier one with this request hash
+    Duplicate(String),
+    Skipped,
+    Stopped,
+}
+
+/// Expands an --input-glob pattern into the files it matches, in sorted order.
+pub fn expand_glob(pattern: &str) -> Result<Vec<String>, String> {
+    let paths = glob::glob(pattern).map_err(|e| format!("'{}' is not a valid pattern: {}", pattern, e))?;
+    let mut files = Vec::new();
+    for path in paths {
+        let path = path.map_err(|e| format!("could not read {}: {}", e.path().display(), e.error()))?;
+        if path.is_file() {
+            files.push(path.to_string_lossy().into_owned());
+        }
+    }
+    Ok(files)
+}
+
+/// How a --batch run sends and prints its inputs.
+pub struct Options {
+    /// Reuse the response of an earlier identical input instead of sending it again
+    pub dedup: bool,
+    /// Print the results together as one JSON array at the end
+    pub json_array: bool,
+    /// Show each input and ask before sending it, one at a time
+    pub confirm_each: bool,
+    /// The most requests to have in flight at once
+    pub concurrency: usize,
+    /// When streaming, print lines as they arrive, prefixed with the input's name
+    pub interleave: bool,
+}
+
+/// Writes streamed text to stdout a line at a time, each line prefixed with the input's
+/// name, so several streams can share the terminal. Blank lines are held back until more
+/// text follows them, so a response's trailing newlines don't print as empty lines.
+struct Prefixed<'a> {
+    name: &'a str,
+    line: Vec<u8>,
+    blank_lines: usize,
+}
+
+impl<'a> Prefixed<'a> {
+    fn new(name: &'a str) -> Self {
+        Prefixed { name, line: Vec::new(), blank_lines: 0 }
+    }
+}
+
+impl Write for Prefixed<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.line.extend_from_slice(buf);
+        while let Some(newline) = self.line.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.line.drain(..=newline).collect();
+            if line.trim_ascii().is_empty() {
+                self.blank_lines += 1;
+                continue;
+            }
+            let mut stdout = std::io::stdout().lock();
+            for _ in 0..std::mem::take(&mut self.blank_lines) {
+                writeln!(stdout, "[{}]", self.name)?;
+            }
+            write!(stdout, "[{}] ", self.name)?;
+            stdout.write_all(&line)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        std::io::stdout().flush()
+    }
+}
+
+/// Sends `template` once per item with the item's input as the user text, and prints each
+/// response in order under a header with the item's name. Streamed responses are printed
+/// whole once complete, unless `options.interleave` prints them line by line as they arrive.
+pub async fn run(api: &Api, template: &ChatRequest, items: Vec<Item>, options: &Options) -> Result<(), Box<dyn std::error::Error>> {
+    let Options { dedup, json_array, confirm_each, interleave, .. } = *options;
+    let concurrency = if confirm_each { 1 } else { options.concurrency.max(1) };
+    let mut seen = HashSet::new();
+    let planned: Vec<(Item, ChatRequest, String, bool)> = items
+        .into_iter()
+        .map(|item| {
+            let mut request = template.clone();
+            if let Some(Content::Text { text }) = request.messages.last_mut().and_then(|m| m.content.first_mut()) {
+                *text = item.input.clone();
+            }
+            let key = request.hash();
+            api.print_hash(&request, Some(&item.name));
+            let duplicate = dedup && !seen.insert(key.clone());
+            (item, request, key, duplicate)
+        })
+        .collect();
+
+    let mut outcomes = stream::iter(planned)
+        .map(|(item, request, key, duplicate)| async move {
+            if duplicate {
+                return Ok((item, key.clone(), Outcome::Duplicate(key)));
+            }
+            if confirm_each {
+                match confirm(&item)? {
+                    Confirmation::Send => {}
+                    Confirmation::Skip => return Ok((item, key, Outcome::Skipped)),
+                    Confirmation::Stop => return Ok((item, key, Outcome::Stopped)),
+                }
+            }
+            let response = match (request.stream, interleave) {
+                (true, true) => api.complete_streamed(&request, &mut Prefixed::new(&item.name)).await,
+                (true, false) => api.complete_streamed(&request, &mut std::io::sink()).await,
+                (false, _) => api.complete_json(&request).await,
+            };
+            Ok::<_, Box<dyn std::error::Error>>((item, key, Outcome::Sent(response.map_err(|e| e.to_string()))))
+        })
+        .buffered(concurrency);
+
+    let mut responses: HashMap<String, Result<Value, String>> = HashMap::new();
+    let mut results = Vec::new();
+    let mut failures = 0;
+
+    while let Some(outcome) = outcomes.next().await {
+        let (item, key, outcome) = outcome?;
+        let response = match outcome {
+            Outcome::Sent(response) => {
+                responses.insert(key, response.clone());
+                response
+            }
+            Outcome::Duplicate(original) => match responses.get(&original) {
+                Some(response) => {
+                    debug!("'{}' is identical to an earlier input; reusing its response", item.name);
+                    // An interleaved response is only printed as it streams, so print the reused one here
+                    if let (true, Ok(response_json)) = (interleave, response) {
+                        let content = response_json["choices"][0]["message"]["content"].as_str().unwrap_or_default();
+                        writeln!(Prefixed::new(&item.name), "{}", content.trim_end())?;
+                    }
+                    response.clone()
+                }
+                // The identical input was skipped, so this one is too
+                None => {
+                    if json_array {
+                        results.push(json!({ "input_name": item.name, "skipped": true }));
+                    }
+                    continue;
+                }
+            },
+            Outcome::Skipped => {
+                if json_array {
+                    results.push(json!({ "input_name": item.name, "skipped": true }));
+                }
+                continue;
+            }
+            Outcome::Stopped => {
+                crate::diagnostics::status("batch_stopped", format!("Stopped before '{}'.", item.name));
+                break;
+            }
+        };
+
+        if let Err(e) = &response {
+            crate::diagnostics::error("batch_item_failed", format!("{}: {}", item.name, e));
+            failures += 1;
+        }
+        if json_array {
+            results.push(match response {
+                Ok(response_json) => json!({
+                    "input_name": item.name,
+                    "content": response_json["choices"][0]["message"]["content"],
+                    "usage": response_json["usage"],
+                }),
+                Err(e) => json!({ "input_name": item.name, "error": e }),
+            });
+        } else if !interleave {
+            println!("===== {} =====", item.name);
+            if let Ok(response_json) = response {
+                println!("{}\n", response_json["choices"][0]["message"]["content"].as_str().unwrap_or_default().trim_end());
+            }
+        }
+    }
+
+    if json_array {
+        println!("{}", serde_json::to_string_pretty(&results)?);
+    }
+    api.report_retry_budget();
+
+    if failures > 0 {
+        crate::diagnostics::error("batch_failed", format!("{} input(s) failed.", failures));
+        std::process::exit(1);
+    }
+    Ok(())
+}
@@ -0,0 +1,172 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+/// A 1x1 transparent PNG, the smallest image the vision probe can send
+const PROBE_IMAGE: &str = "data:image/png;base64,iVBORw0KGgoAAAANSUhEUgAAAAEAAAABCAQAAAC1HAwCAAAAC0lEQVR42mNkYAAAAAYAAjCB0C8AAAAASUVORK5CYII=";
+
+/// How long a probed result is reused before --capabilities probes again
+const CACHE_DAYS: i64 = 7;
+
+/// A feature that is probed, with how the basic one-token probe request is changed to use it.
+struct Feature {
+    name: &'static str,
+    description: &'static str,
+    apply: fn(&mut Value),
+}
+
+const FEATURES: &[Feature] = &[
+    Feature {
+        name: "chat",
+        description: "chat completions",
+        apply: |_| {},
+    },
+    Feature {
+        name: "json_mode",
+        description: "JSON mode (--strict-json, --json-path)",
+        apply: |body| {
+            body["response_format"] = json!({ "type": "json_object" });
+            body["messages"][0]["content"] = json!([{ "type": "text", "text": "Reply in JSON." }]);
+        },
+    },
+    Feature {
+        name: "structured_output",
+        description: "JSON schema output (--schema)",
+        apply: |body| {
+            body["response_format"] = json!({
+                "type": "json_schema",
+                "json_schema": {
+                    "name": "probe",
+                    "strict": true,
+                    "schema": { "type": "object", "properties": { "ok": { "type": "boolean" } }, "required": ["ok"], "additionalProperties": false },
+                },
+            });
+        },
+    },
+    Feature {
+        name: "vision",
+        description: "image input (--image)",
+        apply: |body| {
+            if let Some(parts) = body["messages"][0]["content"].as_array_mut() {
+                parts.push(json!({ "type": "image_url", "image_url": { "url": PROBE_IMAGE } }));
+            }
+        },
+    },
+    Feature {
+        name: "tools",
+        description: "function calling",
+        apply: |body| {
+            body["tools"] = json!([{
+                "type": "function",
+                "function": { "name": "probe", "description": "Does nothing.", "parameters": { "type": "object", "properties": {} } },
+            }]);
+        },
+    },
+];
+
+/// Probed features of one deployment, as kept in the cache file.
+#[derive(Serialize, Deserialize)]
+struct Probed {
+    probed_at: String,
+    /// "yes", "no", or a note on why it couldn't be told
+    features: BTreeMap<String, String>,
+}
+
+fn cache_path() -> Option<PathBuf> {
+    crate::config::config_dir().map(|dir| dir.join("capabilities.json"))
+}
+
+fn load_cache() -> BTreeMap<String, Probed> {
+    cache_path()
+        .and_then(|path| std::fs::read_to_string(path).ok())
+        .and_then(|contents| serde_json::from_str(&contents).ok())
+        .unwrap_or_default()
+}
+
+fn save_cache(cache: &BTreeMap<String, Probed>) -> Result<(), String> {
+    let path = cache_path().ok_or("could not determine the config directory")?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).map_err(|e| format!("could not create {}: {}", dir.display(), e))?;
+    }
+    let contents = serde_json::to_string_pretty(cache).map_err(|e| e.to_string())?;
+    std::fs::write(&path, contents).map_err(|e| format!("could not write {}: {}", path.display(), e))
+}
+
+/// Sends one probe, returning whether the deployment accepted it. A 400 means the feature
+/// isn't supported; authentication and missing deployment errors end the probing.
+async fn probe(client: &reqwest::Client, url: &str, api_key: &str, body: &Value) -> Result<String, Box<dyn std::error::Error>> {
+    let response = client.post(url).header("api-key", api_key).json(body).send().await?;
+    let status = response.status();
+    if status.is_success() {
+        return Ok("yes".to_string());
+    }
+    let response_json: Value = response.json().await.unwrap_or(Value::Null);
+    let message = response_json["error"]["message"].as_str().unwrap_or("no details").to_string();
+    match status.as_u16() {
+        400 => Ok("no".to_string()),
+        401 | 403 | 404 => Err(format!("HTTP {}: {}", status.as_u16(), message).into()),
+        code => Ok(format!("unknown (HTTP {}: {})", code, message)),
+    }
+}
+
+/// Reports which features the deployment supports, probing it with one-token requests
+/// unless a result from the last week is cached (or `refresh` is set).
+pub async fn run(model: Option<String>, config: &crate::config::Config, refresh: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let api_key = crate::get_credential("api_key")?;
+    let endpoint = crate::get_credential("endpoint")?;
+    let deployment = crate::get_deployment(model, config)?;
+    crate::require_credentials(&[("api_key", &api_key), ("endpoint", &endpoint), ("deployment", &deployment)]);
+    let (endpoint, api_key) = match crate::mapped_endpoint(config, &deployment)? {
+        Some(mapped) => (mapped.endpoint, mapped.api_key),
+        None => (endpoint, api_key),
+    };
+
+    let key = format!("{}|{}", endpoint, deployment);
+    let mut cache = load_cache();
+    let fresh = cache.get(&key).filter(|probed| {
+        chrono::DateTime::parse_from_rfc3339(&probed.probed_at)
+            .is_ok_and(|at| chrono::Local::now().signed_duration_since(at) < chrono::Duration::days(CACHE_DAYS))
+    });
+
+    let cached = fresh.is_some() && !refresh;
+    if !cached {
+        let base = crate::ChatRequest {
+            messages: vec![crate::text_message("user", "ping".to_string())],
+            temperature: 0.0,
+            top_p: 1.0,
+            max_tokens: 1,
+            stream: false,
+            stream_options: None,
+            n: None,
+            response_format: None,
+            style: crate::RequestStyle::new(&config.parameters_for(&deployment)),
+        }
+        .body();
+
+        let client = reqwest::Client::new();
+        let url = crate::chat_completions_url(&endpoint, &deployment, config.parameters_for(&deployment).api_version.as_deref());
+        let mut features = BTreeMap::new();
+        for feature in FEATURES {
+            let mut body = base.clone();
+            (feature.apply)(&mut body);
+            let support = probe(&client, &url, &api_key, &body).await.map_err(|e| format!("could not probe deployment '{}': {}", deployment, e))?;
+            features.insert(feature.name.to_string(), support);
+        }
+        cache.insert(key.clone(), Probed { probed_at: chrono::Local::now().to_rfc3339(), features });
+        if let Err(e) = save_cache(&cache) {
+            log::warn!(code = "capabilities_cache"; "{}", e);
+        }
+    }
+
+    let probed = &cache[&key];
+    println!("Deployment: {}", deployment);
+    for feature in FEATURES {
+        let support = probed.features.get(feature.name).map_or("not probed", String::as_str);
+        println!("    {:<20}{:<10}{}", feature.name, support, feature.description);
+    }
+    if cached {
+        println!("Cached from {}; pass --refresh-capabilities to probe again.", probed.probed_at);
+    }
+    Ok(())
+}
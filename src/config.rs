@@ -0,0 +1,117 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use serde::Deserialize;
+use crate::models::ModelLimits;
+
+/// Settings read from `ai.toml` in the user's config directory
+/// (for example `~/.config/ai/ai.toml` or `%APPDATA%\ai\ai.toml`).
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The deployment to use when --model isn't given, instead of the stored credential
+    pub default_deployment: Option<String>,
+    /// Make leaving out --prompt and --preset an error, as --no-default-prompt does
+    pub require_prompt: bool,
+    /// Friendly names for deployments, e.g. `fast = "gpt-4o-mini-2024-07-18"`
+    pub deployments: HashMap<String, String>,
+    /// Deployments that --round-robin takes turns sending requests to
+    pub round_robin: Vec<String>,
+    /// Token limits for deployments the built-in model table doesn't know about
+    pub models: HashMap<String, ModelLimits>,
+    /// Request defaults for particular deployments, keyed by deployment or friendly name
+    pub parameters: HashMap<String, Parameters>,
+    /// Endpoints for deployments that live in a different resource than the stored
+    /// endpoint, keyed by deployment or friendly name
+    pub endpoints: HashMap<String, String>,
+    /// Requests per minute to allow particular deployments, keyed by deployment or friendly
+    /// name. Others use --rate-limit, if given.
+    pub rate_limits: HashMap<String, f64>,
+}
+
+/// Defaults for requests to one deployment. They take precedence over the AI_TEMPERATURE
+/// style environment variables, and the command line takes precedence over them.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Parameters {
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub max_tokens: Option<i32>,
+    /// Leave temperature and top_p out of requests, since reasoning models reject them
+    pub omit_sampling: bool,
+    /// Send the limit as `max_completion_tokens`, which reasoning models require
+    pub max_completion_tokens: bool,
+    /// Send the system prompt with the `developer` role that reasoning models use
+    pub developer_role: bool,
+    /// The `api-version` to request this deployment with, for features only a newer
+    /// (or preview) version has
+    pub api_version: Option<String>,
+}
+
+impl Config {
+    /// Adds friendly names from an --alias-file, keeping any the `[deployments]` table already has.
+    pub fn add_aliases(&mut self, aliases: HashMap<String, String>) {
+        for (name, deployment) in aliases {
+            self.deployments.entry(name).or_insert(deployment);
+        }
+    }
+
+    /// Resolves a `--model` value through the alias table, using it literally if it isn't an alias.
+    pub fn resolve_deployment(&self, name: &str) -> String {
+        self.deployments.get(name).cloned().unwrap_or_else(|| name.to_string())
+    }
+
+    /// Finds the `[parameters]` entry for a deployment, under its own name or a friendly name for it.
+    pub fn parameters_for(&self, deployment: &str) -> Parameters {
+        self.entry_for(&self.parameters, deployment).cloned().unwrap_or_default()
+    }
+
+    /// Finds the `[endpoints]` entry for a deployment, under its own name or a friendly name for it.
+    pub fn endpoint_for(&self, deployment: &str) -> Option<String> {
+        self.entry_for(&self.endpoints, deployment).map(|endpoint| endpoint.trim_end_matches('/').to_string())
+    }
+
+    /// Finds the `[rate_limits]` entry for a deployment, under its own name or a friendly name for it.
+    pub fn rate_limit_for(&self, deployment: &str) -> Option<f64> {
+        self.entry_for(&self.rate_limits, deployment).copied()
+    }
+
+    fn entry_for<'a, T>(&self, table: &'a HashMap<String, T>, deployment: &str) -> Option<&'a T> {
+        table
+            .get(deployment)
+            .or_else(|| table.iter().find(|(name, _)| self.resolve_deployment(name) == deployment).map(|(_, entry)| entry))
+    }
+}
+
+pub fn config_dir() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("ai"))
+}
+
+pub fn config_path() -> Option<PathBuf> {
+    config_dir().map(|dir| dir.join("ai.toml"))
+}
+
+/// Finds the project-wide system prompt prefix: a `.aiprompt` file in the current
+/// directory or the nearest parent that has one.
+pub fn find_project_prompt() -> Option<PathBuf> {
+    let cwd = std::env::current_dir().ok()?;
+    cwd.ancestors().map(|dir| dir.join(".aiprompt")).find(|path| path.is_file())
+}
+
+/// Loads the config file, or the defaults if there isn't one.
+pub fn load() -> Result<Config, String> {
+    let Some(path) = config_path() else {
+        return Ok(Config::default());
+    };
+
+    match std::fs::read_to_string(&path) {
+        Ok(contents) => {
+            let config: Config = toml::from_str(&contents).map_err(|e| format!("invalid config file {}: {}", path.display(), e))?;
+            if let Some((name, limit)) = config.rate_limits.iter().find(|(_, &limit)| !crate::rate_limit::is_valid(limit)) {
+                return Err(format!("invalid config file {}: rate_limits.{} must be a positive number of requests per minute, got {}", path.display(), name, limit));
+            }
+            Ok(config)
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Config::default()),
+        Err(e) => Err(format!("could not read config file {}: {}", path.display(), e)),
+    }
+}
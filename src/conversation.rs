@@ -0,0 +1,74 @@
+use std::path::{Path, PathBuf};
+use crate::Message;
+
+/// The conversation from the most recent run, which `--continue` picks up from.
+pub fn last_path() -> Option<PathBuf> {
+    crate::config::config_dir().map(|dir| dir.join("last_conversation.json"))
+}
+
+/// The name --branch uses for the last conversation rather than a saved session.
+pub const LAST: &str = "last";
+
+/// Where the named session for --session is kept.
+pub fn session_path(name: &str) -> Result<PathBuf, String> {
+    if name.is_empty() || name == LAST || name.starts_with('.') || name.contains(['/', '\\']) {
+        return Err(format!("'{}' can't be used as a session name.", name));
+    }
+    let dir = crate::config::config_dir().ok_or("could not determine the config directory")?;
+    Ok(dir.join("sessions").join(format!("{}.json", name)))
+}
+
+/// Copies the history of the session `from` (or the last conversation, for "last") into a
+/// new session `to`, which must not exist yet. Returns how many messages were copied.
+pub fn branch(from: &str, to: &str) -> Result<usize, String> {
+    let source = match from {
+        LAST => last_path().ok_or("could not determine the config directory")?,
+        name => session_path(name)?,
+    };
+    if !source.exists() {
+        return Err(match from {
+            LAST => "there is no previous conversation to branch from.".to_string(),
+            name => format!("there is no session named '{}'.", name),
+        });
+    }
+    let target = session_path(to)?;
+    if target.exists() {
+        return Err(format!("a session named '{}' already exists.", to));
+    }
+    let messages = load(&source)?;
+    save(&target, &messages)?;
+    Ok(messages.len())
+}
+
+pub fn load(path: &Path) -> Result<Vec<Message>, String> {
+    let contents = std::fs::read_to_string(path).map_err(|e| format!("could not read conversation {}: {}", path.display(), e))?;
+    serde_json::from_str(&contents).map_err(|e| format!("conversation {} is corrupt: {}", path.display(), e))
+}
+
+pub fn save(path: &Path, messages: &[Message]) -> Result<(), String> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).map_err(|e| format!("could not create {}: {}", dir.display(), e))?;
+    }
+    let contents = serde_json::to_string_pretty(messages).map_err(|e| e.to_string())?;
+    std::fs::write(path, contents).map_err(|e| format!("could not save conversation {}: {}", path.display(), e))
+}
+
+/// Removes the oldest messages after the system prompt until the conversation's estimated
+/// size is within `limit` tokens, always keeping the system prompt and the final message.
+/// A reply left without the message it answered is removed too. Returns how many were removed.
+pub fn trim_history(messages: &mut Vec<Message>, limit: usize) -> usize {
+    let first = usize::from(messages.first().is_some_and(|m| m.role == "system"));
+    let size = |messages: &[Message]| messages.iter().map(|m| crate::estimate_tokens(&m.text())).sum::<usize>();
+
+    let mut evicted = 0;
+    while messages.len() > first + 1 {
+        let over_limit = size(messages) > limit;
+        let orphaned_reply = evicted > 0 && messages[first].role == "assistant";
+        if !over_limit && !orphaned_reply {
+            break;
+        }
+        messages.remove(first);
+        evicted += 1;
+    }
+    evicted
+}
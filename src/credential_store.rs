@@ -0,0 +1,254 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Mutex, OnceLock};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+/// Where credentials are kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Backend {
+    /// The operating system's credential manager
+    Keyring,
+    /// A passphrase-encrypted file in the config directory, for systems without a keyring
+    File,
+}
+
+static BACKEND: OnceLock<Backend> = OnceLock::new();
+
+/// Credentials from --credentials-file, which take precedence over the store.
+static CREDENTIALS_FILE: OnceLock<CredentialsFile> = OnceLock::new();
+
+/// A JSON secret holding any of the credentials, such as a mounted Kubernetes secret.
+#[derive(Deserialize)]
+struct CredentialsFile {
+    api_key: Option<String>,
+    endpoint: Option<String>,
+    deployment: Option<String>,
+}
+
+/// The decrypted contents of the credential file, kept so the passphrase is only asked for once.
+static UNLOCKED: Mutex<Option<Unlocked>> = Mutex::new(None);
+
+struct Unlocked {
+    key: [u8; 32],
+    salt: Vec<u8>,
+    values: HashMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct EncryptedFile {
+    version: u32,
+    salt: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+/// Environment variable that supplies the credential file passphrase for unattended runs
+const PASSPHRASE_ENV_VAR: &str = "AI_STORE_PASSPHRASE";
+
+pub fn select(backend: Backend) {
+    let _ = BACKEND.set(backend);
+}
+
+pub fn load_credentials_file(path: &str) -> Result<(), String> {
+    let contents = crate::read_input_to_string(path).map_err(|e| format!("could not read credentials file '{}': {}", path, e))?;
+    let file: CredentialsFile = serde_json::from_str(&contents).map_err(|e| format!("credentials file '{}' is not valid: {}", path, e))?;
+    let _ = CREDENTIALS_FILE.set(file);
+    Ok(())
+}
+
+/// Looks up a credential (`api_key`, `endpoint`, or `deployment`) in the --credentials-file.
+pub fn from_credentials_file(cred_type: &str) -> Option<String> {
+    let file = CREDENTIALS_FILE.get()?;
+    let value = match cred_type {
+        "api_key" => &file.api_key,
+        "endpoint" => &file.endpoint,
+        "deployment" => &file.deployment,
+        _ => &None,
+    };
+    value.as_deref().map(str::trim).filter(|value| !value.is_empty()).map(str::to_string)
+}
+
+pub fn backend() -> Backend {
+    *BACKEND.get().unwrap_or(&Backend::Keyring)
+}
+
+pub fn file_path() -> Option<PathBuf> {
+    crate::config::config_dir().map(|dir| dir.join("credentials.enc"))
+}
+
+/// Reads a credential, returning `None` if it isn't stored.
+pub fn get(id: &str) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    match backend() {
+        Backend::Keyring => match keyring_entry(id)?.get_password() {
+            Ok(value) => Ok(Some(value)),
+            Err(keyring::Error::NoEntry) => Ok(None),
+            Err(e) => Err(keyring_error(e)),
+        },
+        Backend::File => with_unlocked(false, |unlocked| Ok(unlocked.and_then(|u| u.values.get(id).cloned()))),
+    }
+}
+
+pub fn set(id: &str, value: &str) -> Result<(), Box<dyn std::error::Error>> {
+    match backend() {
+        Backend::Keyring => keyring_entry(id)?.set_password(value).map_err(keyring_error),
+        Backend::File => with_unlocked(true, |unlocked| {
+            let unlocked = unlocked.expect("created when missing");
+            unlocked.values.insert(id.to_string(), value.to_string());
+            write_file(unlocked)
+        }),
+    }
+}
+
+/// Removes a credential, returning whether there was one to remove.
+pub fn delete(id: &str) -> Result<bool, Box<dyn std::error::Error>> {
+    match backend() {
+        Backend::Keyring => match keyring_entry(id)?.delete_password() {
+            Ok(()) => Ok(true),
+            Err(keyring::Error::NoEntry) => Ok(false),
+            Err(e) => Err(keyring_error(e)),
+        },
+        Backend::File => with_unlocked(false, |unlocked| {
+            let Some(unlocked) = unlocked else {
+                return Ok(false);
+            };
+            if unlocked.values.remove(id).is_none() {
+                return Ok(false);
+            }
+            write_file(unlocked)?;
+            Ok(true)
+        }),
+    }
+}
+
+fn keyring_entry(id: &str) -> Result<keyring::Entry, Box<dyn std::error::Error>> {
+    keyring::Entry::new("actionitems", id).map_err(keyring_error)
+}
+
+/// Headless machines often have no keyring service at all, which the keyring crate
+/// reports as a bare platform error. Explain what happened and how to work around it
+/// instead of passing that along.
+fn keyring_error(e: keyring::Error) -> Box<dyn std::error::Error> {
+    match e {
+        keyring::Error::PlatformFailure(_) | keyring::Error::NoStorageAccess(_) => {
+            debug!("Keyring error: {:?}", e);
+            let message = [
+                format!("The operating system keyring is not available ({}).", e),
+                "This is common on headless systems and containers. Either:".to_string(),
+                "  - set AZURE_OPENAI_API_KEY, AZURE_OPENAI_ENDPOINT, and AZURE_OPENAI_DEPLOYMENT in the environment or a .env file, or".to_string(),
+                "  - pass --store file to keep credentials in a passphrase-encrypted file instead.".to_string(),
+            ];
+            crate::diagnostics::error("keyring_unavailable", message.join("\n"));
+            std::process::exit(1);
+        }
+        e => e.into(),
+    }
+}
+
+/// Runs `f` with the decrypted credential file, unlocking it first if needed. When the
+/// file doesn't exist yet, `f` gets `None` unless `create` is set, in which case a new
+/// passphrase is chosen.
+fn with_unlocked<T>(create: bool, f: impl FnOnce(Option<&mut Unlocked>) -> Result<T, Box<dyn std::error::Error>>) -> Result<T, Box<dyn std::error::Error>> {
+    let mut guard = UNLOCKED.lock().map_err(|_| "credential store lock poisoned")?;
+    if guard.is_none() {
+        let path = file_path().ok_or("could not determine the config directory")?;
+        if path.exists() {
+            *guard = Some(unlock(&path)?);
+        } else if create {
+            *guard = Some(create_new()?);
+        }
+    }
+    f(guard.as_mut())
+}
+
+fn read_passphrase(prompt: &str) -> Result<String, Box<dyn std::error::Error>> {
+    if let Ok(passphrase) = std::env::var(crate::env_name(PASSPHRASE_ENV_VAR)) {
+        return Ok(passphrase);
+    }
+    eprint!("{}", prompt);
+    Ok(rpassword::read_password()?)
+}
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> Result<[u8; 32], Box<dyn std::error::Error>> {
+    let mut key = [0u8; 32];
+    argon2::Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| format!("could not derive the credential file key: {}", e))?;
+    Ok(key)
+}
+
+fn create_new() -> Result<Unlocked, Box<dyn std::error::Error>> {
+    crate::diagnostics::status("credential_file", "Creating an encrypted credential file.");
+    let passphrase = read_passphrase("Choose a passphrase for the credential file (input will be hidden): ")?;
+    if std::env::var(crate::env_name(PASSPHRASE_ENV_VAR)).is_err() {
+        let confirmation = read_passphrase("Confirm the passphrase: ")?;
+        if confirmation != passphrase {
+            return Err("the passphrases did not match".into());
+        }
+    }
+    if passphrase.is_empty() {
+        return Err("the passphrase cannot be empty".into());
+    }
+
+    let mut salt = vec![0u8; 16];
+    OsRng.fill_bytes(&mut salt);
+    let key = derive_key(&passphrase, &salt)?;
+    Ok(Unlocked { key, salt, values: HashMap::new() })
+}
+
+fn unlock(path: &PathBuf) -> Result<Unlocked, Box<dyn std::error::Error>> {
+    let contents = std::fs::read_to_string(path).map_err(|e| format!("could not read credential file {}: {}", path.display(), e))?;
+    let file: EncryptedFile = serde_json::from_str(&contents).map_err(|e| format!("credential file {} is corrupt: {}", path.display(), e))?;
+    let salt = BASE64.decode(&file.salt)?;
+    let nonce = BASE64.decode(&file.nonce)?;
+    let ciphertext = BASE64.decode(&file.ciphertext)?;
+    if nonce.len() != 12 {
+        return Err(format!("credential file {} is corrupt: bad nonce", path.display()).into());
+    }
+
+    let passphrase = read_passphrase("Credential file passphrase (input will be hidden): ")?;
+    let key = derive_key(&passphrase, &salt)?;
+    let plaintext = ChaCha20Poly1305::new(Key::from_slice(&key))
+        .decrypt(Nonce::from_slice(&nonce), ciphertext.as_slice())
+        .map_err(|_| "incorrect passphrase for the credential file")?;
+    let values = serde_json::from_slice(&plaintext)?;
+    debug!("Unlocked credential file {}", path.display());
+
+    Ok(Unlocked { key, salt, values })
+}
+
+fn write_file(unlocked: &Unlocked) -> Result<(), Box<dyn std::error::Error>> {
+    let path = file_path().ok_or("could not determine the config directory")?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+
+    let plaintext = serde_json::to_vec(&unlocked.values)?;
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = ChaCha20Poly1305::new(Key::from_slice(&unlocked.key))
+        .encrypt(&nonce, plaintext.as_slice())
+        .map_err(|_| "could not encrypt the credential file")?;
+    let file = EncryptedFile {
+        version: 1,
+        salt: BASE64.encode(&unlocked.salt),
+        nonce: BASE64.encode(nonce),
+        ciphertext: BASE64.encode(ciphertext),
+    };
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut out = options.open(&path).map_err(|e| format!("could not write credential file {}: {}", path.display(), e))?;
+    std::io::Write::write_all(&mut out, serde_json::to_string_pretty(&file)?.as_bytes())?;
+    debug!("Wrote credential file {}", path.display());
+    Ok(())
+}
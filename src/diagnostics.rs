@@ -0,0 +1,41 @@
+use std::fmt::Display;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use serde_json::json;
+
+/// Whether --diagnostics-json is on.
+static JSON: AtomicBool = AtomicBool::new(false);
+
+pub fn enable_json() {
+    JSON.store(true, Ordering::Relaxed);
+}
+
+fn emit(level: &str, code: &str, message: &str) {
+    eprintln!("{}", json!({ "level": level, "code": code, "message": message }));
+}
+
+/// Reports an error on stderr, as `Error: message`, or as a JSON object with --diagnostics-json.
+pub fn error(code: &str, message: impl Display) {
+    if JSON.load(Ordering::Relaxed) {
+        emit("error", code, &message.to_string());
+    } else {
+        eprintln!("Error: {}", message);
+    }
+}
+
+/// Reports a status message on stderr, as is, or as a JSON object with --diagnostics-json.
+pub fn status(code: &str, message: impl Display) {
+    if JSON.load(Ordering::Relaxed) {
+        emit("info", code, &message.to_string());
+    } else {
+        eprintln!("{}", message);
+    }
+}
+
+/// Formats log records as JSON objects for --diagnostics-json. Records logged with a
+/// `code` key (as in `warn!(code = "retry"; ...)`) use it; others get the code `log`.
+pub fn format_log(buf: &mut env_logger::fmt::Formatter, record: &log::Record) -> std::io::Result<()> {
+    let code = record.key_values().get(log::kv::Key::from_str("code")).map(|value| value.to_string()).unwrap_or_else(|| "log".to_string());
+    let level = record.level().as_str().to_ascii_lowercase();
+    writeln!(buf, "{}", json!({ "level": level, "code": code, "message": record.args().to_string() }))
+}
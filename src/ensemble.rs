@@ -0,0 +1,61 @@
+use futures::stream::{self, StreamExt};
+use log::info;
+use crate::{text_message, Api, ChatRequest, Content};
+
+/// One of the --prompt values of an --ensemble run.
+pub struct Prompt {
+    pub label: String,
+    pub text: String,
+}
+
+const MERGE_PROMPT: &str = "You combine several answers to the same request into one response. \
+Keep what the answers agree on, resolve disagreements using the most reasonable answer, include \
+useful details that only some answers have, and output only the combined response.";
+
+/// Sends `template` once per prompt, with that prompt as the system message, at most
+/// `concurrency` at a time. Prints each labeled answer, or with `merge`, sends one more
+/// request to combine them and prints only the combined answer.
+pub async fn run(api: &Api, template: &ChatRequest, prompts: Vec<Prompt>, input: &str, merge: bool, concurrency: usize) -> Result<(), Box<dyn std::error::Error>> {
+    let answers: Vec<(String, Result<String, String>)> = stream::iter(prompts)
+        .map(|prompt| async move {
+            let mut request = template.clone();
+            request.messages[0].content = vec![Content::Text { text: prompt.text }];
+            api.print_hash(&request, Some(&prompt.label));
+            let answer = api.complete(&request).await.map_err(|e| e.to_string());
+            (prompt.label, answer)
+        })
+        .buffered(concurrency.max(1))
+        .collect()
+        .await;
+
+    let failures: Vec<_> = answers.iter().filter_map(|(label, answer)| answer.as_ref().err().map(|e| (label, e))).collect();
+    if !failures.is_empty() {
+        for (label, e) in failures {
+            crate::diagnostics::error("ensemble_failed", format!("{}: {}", label, e));
+        }
+        api.report_retry_budget();
+        std::process::exit(1);
+    }
+    let answers: Vec<(String, String)> = answers.into_iter().map(|(label, answer)| (label, answer.unwrap_or_default())).collect();
+
+    if !merge {
+        for (label, answer) in &answers {
+            println!("===== {} =====\n{}\n", label, answer.trim_end());
+        }
+        api.report_retry_budget();
+        return Ok(());
+    }
+
+    let mut transcript = format!("Request:\n{}\n", input.trim_end());
+    for (index, (label, answer)) in answers.iter().enumerate() {
+        info!("Answer from {}:\n{}", label, answer);
+        transcript.push_str(&format!("\n===== Answer {} =====\n{}\n", index + 1, answer.trim_end()));
+    }
+    let mut request = template.clone();
+    request.messages = vec![text_message("system", MERGE_PROMPT.to_string()), text_message("user", transcript)];
+    request.response_format = None;
+    let merged = api.complete(&request).await;
+    api.report_retry_budget();
+    println!("{}", merged?.trim_end());
+    Ok(())
+}
@@ -0,0 +1,78 @@
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use serde_json::{json, Value};
+
+/// What is known about a failed request.
+pub struct Failure<'a> {
+    pub endpoint: Option<&'a str>,
+    pub deployment: Option<&'a str>,
+    pub status: Option<reqwest::StatusCode>,
+    pub headers: Option<&'a reqwest::header::HeaderMap>,
+    pub body: Option<&'a [u8]>,
+    pub error: Option<String>,
+    pub duration: std::time::Duration,
+}
+
+/// Replaces attachment data in a request body with its size, so reports stay small and
+/// don't carry the attached files.
+fn redact(body: &mut Value) {
+    match body {
+        Value::String(text) if text.starts_with("data:") => *text = format!("(attachment, {} bytes)", text.len()),
+        Value::Array(items) => items.iter_mut().for_each(redact),
+        Value::Object(fields) => fields.values_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+/// Writes a report of a failed request to a timestamped file in `dir` for --save-on-error,
+/// returning its path. The API key is never included, since it is only sent as a header.
+pub fn save(dir: &str, request: &crate::ChatRequest, failure: &Failure) -> Result<PathBuf, String> {
+    std::fs::create_dir_all(dir).map_err(|e| format!("could not create '{}': {}", dir, e))?;
+    let now = chrono::Local::now();
+
+    let mut request_body = request.body();
+    redact(&mut request_body);
+    let headers: serde_json::Map<String, Value> = failure
+        .headers
+        .into_iter()
+        .flatten()
+        .map(|(name, value)| (name.to_string(), json!(String::from_utf8_lossy(value.as_bytes()))))
+        .collect();
+    let response_body = failure
+        .body
+        .map(|body| serde_json::from_slice(body).unwrap_or_else(|_| json!(String::from_utf8_lossy(body))));
+
+    let report = json!({
+        "timestamp": now.to_rfc3339(),
+        "duration_ms": failure.duration.as_millis() as u64,
+        "endpoint": failure.endpoint,
+        "deployment": failure.deployment,
+        "status": failure.status.map(|status| status.as_u16()),
+        "error": failure.error,
+        "request": request_body,
+        "response_headers": headers,
+        "response": response_body,
+    });
+    let contents = serde_json::to_string_pretty(&report).map_err(|e| e.to_string())?;
+    let (path, mut file) = create_report_file(Path::new(dir), &now.format("%Y%m%d-%H%M%S-%3f").to_string())?;
+    file.write_all(contents.as_bytes()).map_err(|e| format!("could not write '{}': {}", path.display(), e))?;
+    Ok(path)
+}
+
+/// Creates `ai-error-<stamp>.json` in `dir`, or `ai-error-<stamp>-2.json` and so on when
+/// another failure in the same millisecond, such as a concurrent batch input, took the name.
+fn create_report_file(dir: &Path, stamp: &str) -> Result<(PathBuf, std::fs::File), String> {
+    for attempt in 1.. {
+        let name = match attempt {
+            1 => format!("ai-error-{}.json", stamp),
+            n => format!("ai-error-{}-{}.json", stamp, n),
+        };
+        let path = dir.join(name);
+        match std::fs::OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => return Ok((path, file)),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(format!("could not write '{}': {}", path.display(), e)),
+        }
+    }
+    unreachable!("some attempt finds a free name")
+}
@@ -0,0 +1,91 @@
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+
+/// How many bytes from the start of the file are remembered to notice when it has been replaced
+const FINGERPRINT_LEN: usize = 64;
+
+/// What --since-file remembers between runs.
+#[derive(Serialize, Deserialize)]
+struct State {
+    path: String,
+    offset: u64,
+    /// The first bytes of the file, base64 encoded
+    fingerprint: String,
+}
+
+/// The new part of a file, and the state to record once it has been processed.
+pub struct Increment {
+    pub text: String,
+    state_path: PathBuf,
+    state: State,
+}
+
+impl Increment {
+    /// Records that everything read so far has been processed.
+    pub fn commit(&self) -> Result<(), String> {
+        let contents = serde_json::to_string_pretty(&self.state).map_err(|e| e.to_string())?;
+        fs::write(&self.state_path, contents).map_err(|e| format!("could not write state file {}: {}", self.state_path.display(), e))
+    }
+}
+
+fn fingerprint(file: &mut fs::File) -> std::io::Result<Vec<u8>> {
+    let mut head = Vec::with_capacity(FINGERPRINT_LEN);
+    file.seek(SeekFrom::Start(0))?;
+    file.by_ref().take(FINGERPRINT_LEN as u64).read_to_end(&mut head)?;
+    Ok(head)
+}
+
+/// Reads whatever was added to `path` since the offset recorded in `state_path`. Reading
+/// stops after the last complete line so a line being written isn't split across runs;
+/// it is picked up once its newline has been written.
+/// If the file has shrunk or its first bytes have changed, it was truncated or rotated,
+/// and is read from the start.
+pub fn read_since(path: &str, state_path: &Path, utf8_lossy: bool) -> Result<Increment, String> {
+    let mut file = fs::File::open(path).map_err(|e| format!("could not open '{}': {}", path, e))?;
+    let len = file.metadata().map_err(|e| e.to_string())?.len();
+    let current_fingerprint = fingerprint(&mut file).map_err(|e| e.to_string())?;
+
+    let previous: Option<State> = match fs::read_to_string(state_path) {
+        Ok(contents) => Some(serde_json::from_str(&contents).map_err(|e| format!("state file {} is corrupt: {}", state_path.display(), e))?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => return Err(format!("could not read state file {}: {}", state_path.display(), e)),
+    };
+
+    let offset = match previous {
+        Some(state) if state.path != path => {
+            info!("State file {} was for '{}'; reading '{}' from the start", state_path.display(), state.path, path);
+            0
+        }
+        // A file that was shorter than the fingerprint last time has a shorter fingerprint,
+        // so it only needs to be a prefix of what the file starts with now
+        Some(state) if state.offset > len || !current_fingerprint.starts_with(&BASE64.decode(&state.fingerprint).unwrap_or_default()) => {
+            info!("'{}' was truncated or replaced since the last run; reading it from the start", path);
+            0
+        }
+        Some(state) => state.offset,
+        None => 0,
+    };
+
+    file.seek(SeekFrom::Start(offset)).map_err(|e| e.to_string())?;
+    let mut bytes = crate::read_input_stream(&mut file).map_err(|e| format!("could not read '{}': {}", path, e))?;
+    bytes.truncate(bytes.iter().rposition(|&b| b == b'\n').map_or(0, |last_newline| last_newline + 1));
+    debug!("Read {} new byte(s) of '{}' from offset {}", bytes.len(), path, offset);
+
+    let new_offset = offset + bytes.len() as u64;
+    let text = if utf8_lossy {
+        String::from_utf8_lossy(&bytes).into_owned()
+    } else {
+        String::from_utf8(bytes).map_err(|_| format!("'{}' contains invalid UTF-8 (use --utf8-lossy to replace it)", path))?
+    };
+
+    Ok(Increment {
+        text,
+        state_path: state_path.to_path_buf(),
+        state: State { path: path.to_string(), offset: new_offset, fingerprint: BASE64.encode(current_fingerprint) },
+    })
+}
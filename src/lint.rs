@@ -0,0 +1,56 @@
+use std::collections::HashMap;
+use crate::config::Config;
+use crate::Message;
+
+/// Share of the context window a prompt can fill before it is flagged as suspiciously large
+const LARGE_PROMPT_SHARE: f64 = 0.8;
+
+/// Prompts are flagged above this many tokens when the deployment's limits aren't known
+const LARGE_PROMPT_TOKENS: usize = 100_000;
+
+/// Looks for common mistakes in the assembled messages before they are sent: unfilled
+/// `{{name}}` placeholders, `@file` references to files that don't exist, an empty system
+/// prompt or input, and a prompt close to the deployment's context window. `@file`
+/// references are looked for in the current directory and then the `include_dirs`.
+pub fn check(messages: &[Message], deployment: &str, config: &Config, include_dirs: &[String], check_input: bool) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    for message in messages {
+        let text = message.text();
+        let (_, undefined) = crate::variables::expand(&text, &HashMap::new());
+        for name in undefined {
+            problems.push(format!("the {} message has an unfilled placeholder {{{{{}}}}}", message.role, name));
+        }
+        for word in text.split_whitespace() {
+            let Some(path) = word.strip_prefix('@') else {
+                continue;
+            };
+            let path = path.trim_end_matches([',', ';', ':', ')', '"', '\'']);
+            let looks_like_path = path.contains('/') || path.contains('\\') || path.contains('.');
+            let exists = std::path::Path::new(path).exists() || crate::find_include(path, include_dirs).is_some();
+            if looks_like_path && !path.ends_with('.') && !exists {
+                problems.push(format!("the {} message refers to @{}, which doesn't exist", message.role, path));
+            }
+        }
+    }
+
+    if messages.first().is_some_and(|m| m.role == "system" && m.text().trim().is_empty()) {
+        problems.push("the system prompt is empty".to_string());
+    }
+    if check_input && messages.last().is_some_and(|m| m.role == "user" && m.text().trim().is_empty() && m.content.len() == 1) {
+        problems.push("the input is empty".to_string());
+    }
+
+    let tokens = crate::tokens::count_prompt_tokens(messages, deployment);
+    match crate::models::lookup(deployment, config) {
+        Some((limits, _)) if tokens as f64 > limits.context_window as f64 * LARGE_PROMPT_SHARE => {
+            problems.push(format!("the prompt is about {} tokens, over {}% of the {} token context window", tokens, (LARGE_PROMPT_SHARE * 100.0) as u32, limits.context_window));
+        }
+        None if tokens > LARGE_PROMPT_TOKENS => {
+            problems.push(format!("the prompt is about {} tokens", tokens));
+        }
+        _ => {}
+    }
+
+    problems
+}
@@ -1,174 +1,3054 @@
+use std::collections::HashMap;
 use std::fs;
-use std::io::Write;
-use reqwest;
-use serde_json::{Value};
+use std::io::{ErrorKind, IsTerminal, Read, Write};
+use serde_json::{json, Value};
 use rpassword::read_password;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use clap::Parser;
+use log::{debug, info, trace, warn};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::OnceLock;
+use sha2::{Digest, Sha256};
 
-#[derive(Debug, Serialize)]
+mod attachments;
+mod audit;
+mod batch;
+mod capabilities;
+mod config;
+mod conversation;
+mod credential_store;
+mod diagnostics;
+mod ensemble;
+mod error_report;
+mod incremental;
+mod lint;
+mod models;
+mod output_template;
+mod postprocess;
+mod presets;
+mod rate_limit;
+mod remote_prompt;
+mod round_robin;
+mod schema;
+mod sink;
+mod streaming;
+mod sweep;
+mod tokens;
+mod variables;
+mod watch;
+
+/// Exit code when a request times out or --max-time runs out
+const EXIT_TIMEOUT: i32 = 124;
+
+/// How much --escalate-temp raises the temperature on each retry, and the most it will raise it to
+const ESCALATE_TEMP_STEP: f32 = 0.2;
+const ESCALATE_TEMP_MAX: f32 = 1.5;
+
+/// How many times --min-tokens resends a response that is too short
+const MAX_ELABORATE_RETRIES: u32 = 2;
+
+/// Added to the input when --min-tokens asks for a longer response
+const ELABORATE_INSTRUCTION: &str = "\n\nPlease give a thorough, detailed answer rather than a brief one.";
+
+/// How many times --retry-on-language-mismatch resends a response in the wrong language
+const MAX_LANGUAGE_RETRIES: u32 = 2;
+
+/// Prompts shorter than this aren't eligible for Azure's prompt caching
+const MIN_CACHED_PROMPT_TOKENS: usize = 1024;
+
+/// The most follow-up requests --auto-continue sends for one response
+const MAX_CONTINUATIONS: u32 = 5;
+
+/// Number of requests sent so far, for reporting when --max-time runs out
+static ATTEMPTS: AtomicU32 = AtomicU32::new(0);
+
+/// The largest input file that will be read, from --max-input-bytes (0 for no limit)
+static MAX_INPUT_BYTES: AtomicU64 = AtomicU64::new(0);
+
+/// Put before the names of the environment variables the tool reads, from --env-prefix
+static ENV_PREFIX: OnceLock<String> = OnceLock::new();
+
+/// The name environment variable `name` is read from, after any --env-prefix.
+fn env_name(name: &str) -> String {
+    format!("{}{}", ENV_PREFIX.get().map_or("", String::as_str), name)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct Message {
     role: String,
+    #[serde(deserialize_with = "text_or_parts")]
     content: Vec<Content>,
 }
 
-#[derive(Debug, Serialize)]
-struct Content {
-    #[serde(rename = "type")]
-    content_type: String,
-    text: String,
+/// Reads message content either as a list of parts or, as it is often written by hand,
+/// as a plain string.
+fn text_or_parts<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<Content>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum TextOrParts {
+        Text(String),
+        Parts(Vec<Content>),
+    }
+    Ok(match TextOrParts::deserialize(deserializer)? {
+        TextOrParts::Text(text) => vec![Content::Text { text }],
+        TextOrParts::Parts(parts) => parts,
+    })
+}
+
+impl Message {
+    /// The message's text parts joined together, ignoring any attachments
+    fn text(&self) -> String {
+        self.content
+            .iter()
+            .filter_map(|c| match c {
+                Content::Text { text } => Some(text.as_str()),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Appends to the first text part of the message
+    fn append_text(&mut self, extra: &str) {
+        for content in &mut self.content {
+            if let Content::Text { text } = content {
+                text.push_str(extra);
+                return;
+            }
+        }
+        self.content.push(Content::Text { text: extra.to_string() });
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Content {
+    Text { text: String },
+    ImageUrl { image_url: ImageUrl },
+    File { file: FileData },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ImageUrl {
+    /// A `data:` URL holding the base64 encoded image
+    url: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct FileData {
+    filename: String,
+    /// A `data:` URL holding the base64 encoded file
+    file_data: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct ChatRequest {
+    messages: Vec<Message>,
+    temperature: f32,
+    top_p: f32,
+    max_tokens: i32,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream_options: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    n: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_format: Option<Value>,
+    #[serde(skip)]
+    style: RequestStyle,
+}
+
+/// Differences in how a deployment expects requests to be written, from its `[parameters]` entry.
+#[derive(Debug, Clone, Default)]
+struct RequestStyle {
+    omit_sampling: bool,
+    max_completion_tokens: bool,
+    developer_role: bool,
+}
+
+impl RequestStyle {
+    fn new(parameters: &config::Parameters) -> Self {
+        RequestStyle {
+            omit_sampling: parameters.omit_sampling,
+            max_completion_tokens: parameters.max_completion_tokens,
+            developer_role: parameters.developer_role,
+        }
+    }
+}
+
+impl ChatRequest {
+    /// The JSON body to send, written the way the deployment expects.
+    fn body(&self) -> Value {
+        let mut body = serde_json::to_value(self).unwrap_or_default();
+        let Some(fields) = body.as_object_mut() else {
+            return body;
+        };
+        if self.style.omit_sampling {
+            fields.remove("temperature");
+            fields.remove("top_p");
+        }
+        if self.style.max_completion_tokens {
+            if let Some(limit) = fields.remove("max_tokens") {
+                fields.insert("max_completion_tokens".to_string(), limit);
+            }
+        }
+        if self.style.developer_role {
+            for message in fields.get_mut("messages").and_then(Value::as_array_mut).into_iter().flatten() {
+                if message["role"] == "system" {
+                    message["role"] = json!("developer");
+                }
+            }
+        }
+        body
+    }
+
+    /// A SHA-256 of the request body with its keys sorted, so the same prompt, input, and
+    /// parameters always give the same hash. --batch uses it to spot identical inputs.
+    fn hash(&self) -> String {
+        fn canonical(value: &Value) -> Value {
+            match value {
+                Value::Object(fields) => {
+                    let mut sorted: Vec<_> = fields.iter().collect();
+                    sorted.sort_by(|a, b| a.0.cmp(b.0));
+                    Value::Object(sorted.into_iter().map(|(key, value)| (key.clone(), canonical(value))).collect())
+                }
+                Value::Array(items) => Value::Array(items.iter().map(canonical).collect()),
+                value => value.clone(),
+            }
+        }
+        let digest = Sha256::digest(canonical(&self.body()).to_string().as_bytes());
+        format!("sha256:{:x}", digest)
+    }
+}
+
+#[derive(Parser)]
+#[command(name = "ai")]
+#[command(group(clap::ArgGroup::new("batch_inputs").multiple(true).args(["batch", "input_glob"])))]
+#[command(group(clap::ArgGroup::new("multiple_results").multiple(true).args(["batch", "input_glob", "temperature_sweep"])))]
+#[command(about = "A command line tool for interacting with Azure OpenAI services")]
+#[command(after_help = "\
+CREDENTIALS:
+    The tool securely stores the following credentials:
+    - Azure OpenAI API Key
+    - Azure OpenAI Endpoint
+    - Azure OpenAI Deployment Name
+
+    On first launch, you will be prompted to enter these credentials.
+    They will be stored securely in the system keyring for future use, or in
+    an encrypted file in the config directory with --store file.
+    Use --setup to enter and verify them all at once, or --set-credential to
+    replace a single one.
+    Use --delete-keys to remove stored credentials.
+
+    The AZURE_OPENAI_API_KEY, AZURE_OPENAI_ENDPOINT, and AZURE_OPENAI_DEPLOYMENT
+    environment variables override the stored values. They can also be set in a
+    .env file in the current directory, or in the file given by --env-file.
+    With --env-prefix PREFIX, the tool reads PREFIXAZURE_OPENAI_API_KEY and so on
+    instead, so several configurations can share one environment.
+    Values in a --credentials-file JSON secret override both.")]
+struct Cli {
+    /// Input to process - either a file path or direct text. To pick a deployment
+    /// quickly, put +DEPLOYMENT before it, as in: ai +fast "question"
+    #[arg(index = 1)]
+    input: Option<String>,
+
+    /// The input, when the first argument is +DEPLOYMENT
+    #[arg(index = 2, value_name = "INPUT", hide = true)]
+    input_after_deployment: Option<String>,
+
+    /// System prompt from file, http(s) URL, or direct text. Can be repeated with --ensemble.
+    #[arg(long, value_name = "PROMPT")]
+    prompt: Vec<String>,
+
+    /// System prompt from a file at a git revision, as REF:PATH (for example
+    /// HEAD~1:prompts/review.txt), read with `git show` in the current repository
+    #[arg(long, value_name = "REF:PATH", conflicts_with_all = ["prompt", "ensemble"])]
+    prompt_git: Option<String>,
+
+    /// A directory to look in for --prompt files (and the @file references --lint checks)
+    /// that aren't in the current directory. Can be repeated to search several in order.
+    #[arg(long, value_name = "DIR")]
+    include_dir: Vec<String>,
+
+    /// Send the input with each --prompt and print each answer under a label
+    #[arg(long, requires = "prompt", conflicts_with_all = ["batch_inputs", "continue_conversation", "stream"])]
+    ensemble: bool,
+
+    /// With --ensemble, combine the answers with one more request and print only that
+    #[arg(long, requires = "ensemble")]
+    merge: bool,
+
+    /// Maximum number of requests to have in flight at once with --ensemble, --batch, or
+    /// --temperature-sweep
+    #[arg(long, value_name = "N", default_value_t = 4)]
+    concurrency: usize,
+
+    /// Text to put before the input in the user message, from file or direct text
+    #[arg(long, value_name = "TEXT")]
+    prepend: Option<String>,
+
+    /// Text to put after the input in the user message, from file or direct text
+    #[arg(long, value_name = "TEXT")]
+    append: Option<String>,
+
+    /// Don't put the contents of the nearest .aiprompt file before the system prompt
+    #[arg(long)]
+    no_project_prompt: bool,
+
+    /// When the service returns an error (other than a rejected API key), ask the model
+    /// to explain it in plain language
+    #[arg(long)]
+    explain_error: bool,
+
+    /// Continue the previous conversation, sending its history along with this input
+    #[arg(long = "continue")]
+    continue_conversation: bool,
+
+    /// Keep the conversation in a named session: continue it if it exists, and save this
+    /// exchange to it
+    #[arg(long, value_name = "NAME", conflicts_with_all = ["continue_conversation", "messages", "batch_inputs", "ensemble", "temperature_sweep"])]
+    session: Option<String>,
+
+    /// Copy a saved session (or "last", the previous conversation) into a new session, to
+    /// take it in a different direction with --session NEW_NAME without changing the original
+    #[arg(long, num_args = 2, value_names = ["SESSION", "NEW_NAME"])]
+    branch: Option<Vec<String>>,
+
+    /// JSON file of example messages, such as alternating user and assistant turns for
+    /// few-shot prompting, to send between the system prompt and the input. Can be an
+    /// http(s) URL.
+    #[arg(long, value_name = "PATH", conflicts_with = "continue_conversation")]
+    messages: Option<String>,
+
+    /// Drop the oldest messages from a continued conversation until it fits in about
+    /// this many tokens. The system prompt and the new input are always kept.
+    #[arg(long, value_name = "TOKENS")]
+    history_limit: Option<usize>,
+
+    /// With --history-limit, summarize the oldest messages into a short note instead of
+    /// dropping them. This costs an extra request whenever the history is over the limit.
+    #[arg(long, requires = "history_limit")]
+    compact_history: bool,
+
+    /// Combine several files into a single input, each headed by its file name
+    #[arg(long, value_name = "FILE", num_args = 1.., conflicts_with = "input")]
+    concat: Option<Vec<String>>,
+
+    /// Send a unified diff of two files as the input, asking for a review of the changes
+    /// unless another prompt is given
+    #[arg(long, num_args = 2, value_names = ["OLD", "NEW"], conflicts_with_all = ["input", "concat", "batch_inputs"])]
+    diff_input: Option<Vec<String>>,
+
+    /// Process each file as a separate input with the same prompt, printing each
+    /// response under a header with the file name
+    #[arg(long, value_name = "FILE", num_args = 1.., conflicts_with_all = ["input", "concat", "continue_conversation"])]
+    batch: Option<Vec<String>>,
+
+    /// Process each file matching a glob pattern, such as "logs/*.txt", as a --batch input.
+    /// Quote the pattern so the shell leaves it alone. Can be repeated.
+    #[arg(long, value_name = "PATTERN", conflicts_with_all = ["input", "concat", "continue_conversation"])]
+    input_glob: Vec<String>,
+
+    /// With --batch or --input-glob, send every input even when several are identical
+    #[arg(long, requires = "batch_inputs")]
+    no_dedup: bool,
+
+    /// With --batch or --input-glob, print a single JSON array of {input_name, content, usage} results
+    /// instead of headed text (with --temperature-sweep, {temperature, content, usage}).
+    /// Failed requests get an entry with an "error" field.
+    #[arg(long, requires = "multiple_results")]
+    output_json_array: bool,
+
+    /// With --batch or --input-glob, show each input and ask whether to send it, skip it, or stop
+    #[arg(long, requires = "batch_inputs")]
+    confirm_each: bool,
+
+    /// With --batch and --stream, the most responses to stream at once (instead of
+    /// --concurrency). Each response is printed whole, in order, once it is complete.
+    #[arg(long, value_name = "N", requires = "stream")]
+    max_concurrent_streams: Option<usize>,
+
+    /// With --batch and --stream, print the responses' lines as they arrive, each prefixed
+    /// with the input's name, instead of printing each response whole
+    #[arg(long, requires_all = ["batch_inputs", "stream"], conflicts_with = "output_json_array")]
+    interleave: bool,
+
+    /// Answer yes to --confirm-each, for unattended runs
+    #[arg(long)]
+    yes: bool,
+
+    /// Attach an image to the input, or "-" to read one piped to stdin. Can be repeated.
+    #[arg(long, value_name = "PATH")]
+    image: Vec<String>,
+
+    /// Attach a document such as a PDF to the input, for deployments that accept
+    /// file inputs. Can be repeated.
+    #[arg(long, value_name = "PATH")]
+    file: Vec<String>,
+
+    /// Use a built-in system prompt (see --list-presets). With --prompt, the two are
+    /// combined as --prompt-mode says.
+    #[arg(long, value_name = "NAME")]
+    preset: Option<String>,
+
+    /// How --prompt combines with the project prompt (.aiprompt) and --preset: "append"
+    /// puts it after them, "prepend" before them, and "replace" leaves them out
+    #[arg(long, value_name = "MODE", value_enum, default_value_t = presets::PromptMode::Append)]
+    prompt_mode: presets::PromptMode,
+
+    /// Fail instead of using the default system prompt when no --prompt, --preset, or
+    /// --persona is given
+    #[arg(long)]
+    no_default_prompt: bool,
+
+    /// List the built-in system prompt presets
+    #[arg(long)]
+    list_presets: bool,
+
+    /// Chat with a named character instead of the plain assistant (see --list-personas)
+    #[arg(long, value_name = "NAME", conflicts_with_all = ["prompt", "preset"])]
+    persona: Option<String>,
+
+    /// List the built-in personas
+    #[arg(long)]
+    list_personas: bool,
+
+    /// Show the known context window and output limits of the deployment
+    #[arg(long)]
+    show_model_info: bool,
+
+    /// Check the assembled prompt for common mistakes before sending it, such as unfilled
+    /// {{name}} placeholders, @file references to missing files, an empty system prompt,
+    /// or a prompt near the context window, and warn about them
+    #[arg(long)]
+    lint: bool,
+
+    /// Like --lint, but don't send the request if any problems are found
+    #[arg(long)]
+    lint_strict: bool,
+
+    /// Probe which features (JSON mode, schemas, images, tools) the deployment supports.
+    /// Results are cached for a week.
+    #[arg(long)]
+    capabilities: bool,
+
+    /// Like --capabilities, but probe again even if a cached result exists
+    #[arg(long)]
+    refresh_capabilities: bool,
+
+    /// Print the settings that would be used and where each comes from (flag, environment,
+    /// config file, credential store, or default), without sending anything
+    #[arg(long)]
+    dump_config: bool,
+
+    /// Interactively set up and verify all credentials
+    #[arg(long)]
+    setup: bool,
+
+    /// Enter and store a single credential, replacing the stored value
+    #[arg(long, value_name = "CREDENTIAL", value_parser = ["api_key", "endpoint", "deployment"])]
+    set_credential: Option<String>,
+
+    /// Send a minimal request to verify the stored credentials work
+    #[arg(long)]
+    test: bool,
+
+    /// Check that the deployment responds and print one status line with the latency,
+    /// exiting non-zero if it doesn't. Meant for monitoring probes.
+    #[arg(long)]
+    health: bool,
+
+    /// Delete all stored credentials
+    #[arg(long)]
+    delete_keys: bool,
+
+    /// Where credentials are stored. The file store is encrypted with a passphrase, which
+    /// can also be supplied in the AI_STORE_PASSPHRASE environment variable.
+    #[arg(long, value_enum, default_value = "keyring")]
+    store: credential_store::Backend,
+
+    /// Read credentials from a JSON file with "api_key", "endpoint", and "deployment"
+    /// fields, such as a mounted secret. These take precedence over the environment
+    /// and the credential store.
+    #[arg(long, value_name = "PATH")]
+    credentials_file: Option<String>,
+
+    /// Exit with an error if the model refuses or the response is filtered
+    #[arg(long)]
+    fail_on_refusal: bool,
+
+    /// Deployment to use instead of the stored one. Aliases from the
+    /// [deployments] table in ai.toml are resolved to their deployment names.
+    #[arg(long, value_name = "NAME")]
+    model: Option<String>,
+
+    /// Find the resource's chat deployment instead of using the stored one, and store
+    /// it for future runs. Fails if the resource has more than one.
+    #[arg(long, conflicts_with = "model")]
+    autodetect_deployment: bool,
+
+    /// Send each request to the next of the round_robin deployments in ai.toml, to spread
+    /// load across them. The position is remembered between runs.
+    #[arg(long, conflicts_with_all = ["model", "autodetect_deployment"])]
+    round_robin: bool,
+
+    /// Send the request once at each of these temperatures, such as 0.0,0.5,1.0, and print
+    /// each answer under a label with its temperature
+    #[arg(long, value_name = "T1,T2,...", value_delimiter = ',', value_parser = parse_temperature,
+          conflicts_with_all = ["temperature", "batch_inputs", "ensemble", "continue_conversation", "stream"])]
+    temperature_sweep: Option<Vec<f32>>,
+
+    /// Sampling temperature [env: AI_TEMPERATURE] [default: 0.7]
+    #[arg(long, value_name = "VALUE")]
+    temperature: Option<f32>,
+
+    /// Nucleus sampling probability [env: AI_TOP_P] [default: 0.95]
+    #[arg(long, value_name = "VALUE")]
+    top_p: Option<f32>,
+
+    /// Maximum number of tokens in the response [env: AI_MAX_TOKENS] [default: 16384]
+    #[arg(long, value_name = "TOKENS")]
+    max_tokens: Option<i32>,
+
+    /// Send --max-tokens as given even when it is more than the deployment's known output
+    /// limit, instead of lowering it to the limit
+    #[arg(long)]
+    no_clamp: bool,
+
+    /// Before sending, print the prompt token count, the --max-tokens ceiling, and
+    /// their sum to stderr, to show the worst case cost of the request
+    #[arg(long)]
+    count_both: bool,
+
+    /// Report how many prompt tokens were read from the service's prompt cache. Azure
+    /// caches prompts of 1024 tokens or more automatically, so repeated calls that share
+    /// a large system prompt reuse it; this shows whether that is happening.
+    #[arg(long)]
+    cache_prompt: bool,
+
+    /// Deployment to fail over to when the previous one is still throttled after
+    /// retries. Can be repeated; deployments are tried in order.
+    #[arg(long, value_name = "NAME")]
+    fallback_deployment: Vec<String>,
+
+    /// Endpoint to fail over to when the previous one is unreachable or returns a server
+    /// error. Can be repeated. Each endpoint's API key is stored separately, and is
+    /// also removed by --delete-keys when given together with this option.
+    #[arg(long, value_name = "URL")]
+    fallback_endpoint: Vec<String>,
+
+    /// Number of times to retry throttled (429) and server error (5xx) responses
+    #[arg(long, value_name = "N", default_value_t = 3)]
+    max_retries: u32,
+
+    /// Number of times to resend the request when the response is empty
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    retry_empty: u32,
+
+    /// With --retry-empty, raise the temperature by 0.2 on each retry (up to 1.5)
+    /// to shake the model out of producing nothing
+    #[arg(long, requires = "retry_empty")]
+    escalate_temp: bool,
+
+    /// When the response stops at --max-tokens, ask for the rest and join the parts
+    #[arg(long, conflicts_with = "stream")]
+    auto_continue: bool,
+
+    /// Resend the request, asking for more detail, when the response is shorter than
+    /// this many tokens (up to 2 times)
+    #[arg(long, value_name = "TOKENS", conflicts_with = "stream")]
+    min_tokens: Option<usize>,
+
+    /// Print the request ID the service assigned to stderr, for correlating with Azure
+    /// support. It is always printed with errors.
+    #[arg(long)]
+    show_request_id: bool,
+
+    /// Time limit in seconds for each attempt, including reading the response
+    #[arg(long, value_name = "SECONDS", value_parser = parse_seconds)]
+    timeout: Option<f64>,
+
+    /// How long in seconds to keep an idle connection open for the next request of the same
+    /// run (batch inputs, ensemble prompts, retries, continuations) to reuse.
+    /// 0 opens a new connection for every request.
+    #[arg(long, value_name = "SECONDS", default_value_t = 90.0, value_parser = parse_seconds)]
+    keep_alive: f64,
+
+    /// Overall time limit in seconds for the request, including all retries and the waits
+    /// between them. Exits with code 124 when it runs out, as a timed out request does.
+    #[arg(long, value_name = "SECONDS", value_parser = parse_seconds)]
+    max_time: Option<f64>,
+
+    /// Number of times to retry a request that timed out. Counted separately from
+    /// --max-retries, since large prompts that time out usually time out again.
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    timeout_retries: u32,
+
+    /// Most retries (of any kind) to make across all the requests of the run, such as the
+    /// inputs of a --batch. Once they are used up, failed requests aren't retried.
+    #[arg(long, value_name = "N")]
+    total_retries: Option<u32>,
+
+    /// Send at most this many requests per minute to each deployment that has no entry in
+    /// the [rate_limits] table of ai.toml, waiting as needed
+    #[arg(long, value_name = "RPM", value_parser = rate_limit::parse)]
+    rate_limit: Option<f64>,
+
+    /// Run the request again whenever this file (such as the input or prompt file) changes,
+    /// clearing the screen for each fresh response. Can be repeated. Ctrl-C stops watching.
+    #[arg(long, value_name = "FILE")]
+    watch: Vec<String>,
+
+    /// Warn when the response doesn't appear to be in this language, given as an ISO 639-3
+    /// code (such as fra or deu) or an English name. Short or mixed responses aren't checked.
+    #[arg(long, value_name = "LANGUAGE", value_parser = postprocess::parse_language)]
+    response_language: Option<whatlang::Lang>,
+
+    /// With --response-language, resend the request (up to twice) asking for that language
+    /// instead of only warning
+    #[arg(long, requires = "response_language", conflicts_with = "stream")]
+    retry_on_language_mismatch: bool,
+
+    /// Exit with an error, after printing the response, if it doesn't contain this text.
+    /// Can be repeated; every assertion is checked.
+    #[arg(long, value_name = "TEXT", conflicts_with_all = ["batch_inputs", "ensemble", "temperature_sweep"])]
+    assert_contains: Vec<String>,
+
+    /// Exit with an error, after printing the response, if it doesn't match this regular
+    /// expression. Can be repeated; every assertion is checked.
+    #[arg(long, value_name = "REGEX", value_parser = postprocess::parse_regex, conflicts_with_all = ["batch_inputs", "ensemble", "temperature_sweep"])]
+    assert_matches: Vec<regex::Regex>,
+
+    /// Send a one-token request first to wake the deployment up, and report how long it took.
+    /// Without an input, only the warmup request is sent, for the start of a batch job.
+    #[arg(long)]
+    warmup: bool,
+
+    /// Print the response as it is generated
+    #[arg(long)]
+    stream: bool,
+
+    /// When streaming, ask for the token usage in a final chunk and print it once the
+    /// response is complete. Deployments without an api_version in [parameters] are sent
+    /// api-version 2024-10-21, since the default one rejects the usage option.
+    #[arg(long, requires = "stream")]
+    stream_usage: bool,
+
+    /// When streaming, flush output every N characters (0 flushes only at the end).
+    /// Defaults to every chunk on a terminal and to buffered output otherwise.
+    #[arg(long, value_name = "N", requires = "stream")]
+    flush_every: Option<usize>,
+
+    /// When streaming, give up if nothing arrives for this many seconds, keeping the
+    /// partial response. Unlike --timeout, this only catches a stream that stalls.
+    #[arg(long, value_name = "SECONDS", requires = "stream", value_parser = parse_seconds)]
+    stream_idle_timeout: Option<f64>,
+
+    /// Show details such as the deployment used and retries on stderr (same as --log-level info)
+    #[arg(short, long)]
+    verbose: bool,
+
+    /// Log level for diagnostic messages on stderr (overrides RUST_LOG)
+    #[arg(long, value_name = "LEVEL", value_parser = ["error", "warn", "info", "debug", "trace"])]
+    log_level: Option<String>,
+
+    /// Write errors, warnings, and status messages to stderr as JSON objects, one per
+    /// line, each with a "level", "code", and "message"
+    #[arg(long)]
+    diagnostics_json: bool,
+
+    /// Format the output with a template such as "Result: {content}\nTokens: {total_tokens}".
+    /// Placeholders: content, prompt_tokens, completion_tokens, total_tokens, model, finish_reason
+    #[arg(long, value_name = "TEMPLATE", conflicts_with_all = ["stream", "batch_inputs", "ensemble", "temperature_sweep"])]
+    output_template: Option<String>,
+
+    /// Constrain the response to the JSON Schema in this file, and fail if it doesn't conform
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["batch_inputs", "ensemble", "temperature_sweep"])]
+    schema: Option<String>,
+
+    /// Remove reasoning blocks (<think>...</think> by default) from the response.
+    /// With --verbose, the removed reasoning is shown on stderr.
+    #[arg(long, conflicts_with_all = ["stream", "batch_inputs", "ensemble", "temperature_sweep"])]
+    strip_thinking: bool,
+
+    /// Tag that opens a reasoning block for --strip-thinking
+    #[arg(long, value_name = "TAG", default_value = "<think>")]
+    thinking_open: String,
+
+    /// Tag that closes a reasoning block for --strip-thinking
+    #[arg(long, value_name = "TAG", default_value = "</think>")]
+    thinking_close: String,
+
+    /// Remove trailing offers of further help, such as "Let me know if you need anything
+    /// else!", from the response. With --verbose, the removed text is shown on stderr.
+    #[arg(long, conflicts_with_all = ["stream", "batch_inputs", "ensemble", "temperature_sweep"])]
+    no_filler: bool,
+
+    /// Print the response as a JSON object with the content, model, finish reason, and usage
+    #[arg(long, conflicts_with_all = ["stream", "output_template", "batch_inputs", "ensemble", "temperature_sweep"])]
+    json: bool,
+
+    /// Print why generation stopped (stop, length, content_filter, tool_calls) on stderr.
+    /// With --json the finish reason is already a field of the output.
+    #[arg(long)]
+    print_finish_reason: bool,
+
+    /// Print the response body exactly as the server sent it, without parsing it or
+    /// applying any other output options. Useful for debugging API changes.
+    #[arg(long, conflicts_with_all = ["stream", "json", "output_template", "strip_thinking", "batch_inputs", "ensemble", "temperature_sweep"])]
+    raw_response: bool,
+
+    /// Write the response body as the server sent it, and the content, finish reason, and
+    /// usage the tool read from it, to this file, while printing the response as usual.
+    /// Useful for debugging output that looks wrong.
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["stream", "raw_response", "batch_inputs", "ensemble", "temperature_sweep"])]
+    debug_dual: Option<String>,
+
+    /// Ask for JSON output, and fail if the response isn't valid JSON
+    #[arg(long, conflicts_with_all = ["batch_inputs", "ensemble", "temperature_sweep"])]
+    strict_json: bool,
+
+    /// With --strict-json, pretty-print the returned JSON
+    #[arg(long, requires = "strict_json", conflicts_with_all = ["json_minify", "stream"])]
+    json_pretty: bool,
+
+    /// With --strict-json, print the returned JSON on a single line
+    #[arg(long, requires = "strict_json", conflicts_with = "stream")]
+    json_minify: bool,
+
+    /// Turn near-valid JSON in the response (trailing commas, unquoted keys, a code fence)
+    /// into strict JSON before any other JSON checks, and report whether it was needed
+    #[arg(long, conflicts_with_all = ["stream", "batch_inputs", "ensemble", "temperature_sweep"])]
+    repair_json: bool,
+
+    /// Ask for JSON output and print only the values at this JSONPath (for example
+    /// '$.items[0].name'), failing if nothing matches
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["stream", "json_pretty", "json_minify", "batch_inputs", "ensemble", "temperature_sweep"])]
+    json_path: Option<String>,
+
+    /// Print the system prompt and input before the response (as fields with --json)
+    #[arg(long, conflicts_with_all = ["batch_inputs", "ensemble", "temperature_sweep"])]
+    echo_prompt: bool,
+
+    /// Pipe the response through a shell command and print its output instead
+    #[arg(long, value_name = "COMMAND", conflicts_with_all = ["stream", "batch_inputs", "ensemble", "temperature_sweep"])]
+    pipe_to: Option<String>,
+
+    /// Also write the response to this file
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["batch_inputs", "ensemble", "temperature_sweep"])]
+    output: Option<String>,
+
+    /// Text encoding for the --output file, for tools that don't read UTF-8
+    #[arg(long, value_enum, value_name = "ENCODING", default_value = "utf8", requires = "output")]
+    output_encoding: sink::Encoding,
+
+    /// Print at most this many characters of the response (not counting line breaks),
+    /// ending with an ellipsis. The --output file still gets all of it.
+    #[arg(long, value_name = "CHARS", conflicts_with_all = ["batch_inputs", "ensemble", "temperature_sweep"])]
+    truncate_output: Option<usize>,
+
+    /// Write each candidate response to its own file in this directory (candidate_1.txt,
+    /// candidate_2.txt, ...) as well as printing the first one
+    #[arg(long, value_name = "DIR", conflicts_with_all = ["batch_inputs", "ensemble", "temperature_sweep"])]
+    output_split: Option<String>,
+
+    /// Ask for this many candidate responses, for --output-split to write out
+    #[arg(long, value_name = "N", requires = "output_split", conflicts_with = "stream", value_parser = clap::value_parser!(u32).range(1..=128))]
+    n: Option<u32>,
+
+    /// With --output, don't print the response to stdout
+    #[arg(long, requires = "output")]
+    output_only: bool,
+
+    /// Text to print immediately before the response, e.g. "<answer>"
+    #[arg(long, value_name = "TEXT", default_value = "", hide_default_value = true, conflicts_with_all = ["json", "batch_inputs", "ensemble", "temperature_sweep"])]
+    response_prefix: String,
+
+    /// Text to print immediately after the response, e.g. "</answer>"
+    #[arg(long, value_name = "TEXT", default_value = "", hide_default_value = true, conflicts_with_all = ["json", "batch_inputs", "ensemble", "temperature_sweep"])]
+    response_suffix: String,
+
+    /// Don't end the output with a newline, for when the exact bytes matter, such as a
+    /// value to embed in another file
+    #[arg(long, conflicts_with_all = ["batch_inputs", "ensemble", "temperature_sweep"])]
+    no_trailing_newline: bool,
+
+    /// Print a SHA-256 of each request (prompt, input, and parameters) to stderr, to match
+    /// outputs up with exactly what produced them
+    #[arg(long)]
+    print_hash: bool,
+
+    /// When a request fails, write its (redacted) request body, the response and its
+    /// headers, and the timing to a timestamped file in this directory
+    #[arg(long, value_name = "DIR")]
+    save_on_error: Option<String>,
+
+    /// Append a JSON line describing each request (time, deployment, status, usage) to this file
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["batch_inputs", "ensemble", "temperature_sweep"])]
+    audit_log: Option<String>,
+
+    /// Label recorded with the request in the audit log, as KEY=VALUE. Can be repeated.
+    /// Tags are never sent to the service.
+    #[arg(long, value_name = "KEY=VALUE", value_parser = parse_key_value, requires = "audit_log")]
+    tag: Vec<(String, String)>,
+
+    /// Load environment variables from this file instead of ./.env
+    #[arg(long, value_name = "PATH")]
+    env_file: Option<String>,
+
+    /// Put PREFIX before the names of the environment variables the tool reads, as in
+    /// TENANT1_AZURE_OPENAI_API_KEY for --env-prefix TENANT1_
+    #[arg(long, value_name = "PREFIX")]
+    env_prefix: Option<String>,
+
+    /// Replace invalid UTF-8 in input files instead of failing
+    #[arg(long)]
+    utf8_lossy: bool,
+
+    /// Refuse to read input files larger than this, so a mistaken path can't exhaust
+    /// memory. 0 removes the limit.
+    #[arg(long, value_name = "BYTES", default_value_t = 10 * 1024 * 1024)]
+    max_input_bytes: u64,
+
+    /// Only send the part of the input file added since the last run, keeping track of
+    /// how far it has been read in this state file. Starts over if the file is truncated
+    /// or replaced.
+    #[arg(long, value_name = "STATE_FILE", requires = "input", conflicts_with_all = ["concat", "batch_inputs"])]
+    since_file: Option<String>,
+
+    /// Replace {{NAME}} in the prompt and input with VALUE. Can be repeated, and overrides
+    /// --var-file.
+    #[arg(long = "var", value_name = "NAME=VALUE", value_parser = parse_key_value)]
+    var: Vec<(String, String)>,
+
+    /// Load friendly deployment names (such as reviewer = "gpt-4o-2024-08-06") from a shared
+    /// TOML, JSON, or name=value file, for --model and the config file to use. Names in
+    /// the config file's [deployments] table take precedence.
+    #[arg(long, value_name = "PATH")]
+    alias_file: Option<String>,
+
+    /// Load {{NAME}} template variables from a TOML, JSON, or env-style (NAME=value) file
+    #[arg(long, value_name = "PATH")]
+    var_file: Option<String>,
+}
+
+fn parse_key_value(arg: &str) -> Result<(String, String), String> {
+    match arg.split_once('=') {
+        Some((key, value)) if !key.trim().is_empty() => Ok((key.trim().to_string(), value.to_string())),
+        _ => Err(format!("expected KEY=VALUE, got '{}'", arg)),
+    }
+}
+
+/// Parses a number of seconds, rejecting values a Duration can't hold (negative, infinite, NaN).
+fn parse_seconds(arg: &str) -> Result<f64, String> {
+    match arg.trim().parse::<f64>() {
+        Ok(seconds) if std::time::Duration::try_from_secs_f64(seconds).is_ok() => Ok(seconds),
+        Ok(_) => Err(format!("expected a number of seconds, 0 or more, got '{}'", arg)),
+        Err(_) => Err(format!("expected a number, got '{}'", arg)),
+    }
+}
+
+fn parse_temperature(arg: &str) -> Result<f32, String> {
+    match arg.trim().parse::<f32>() {
+        Ok(temperature) if (0.0..=2.0).contains(&temperature) => Ok(temperature),
+        Ok(_) => Err(format!("temperatures range from 0 to 2, got '{}'", arg)),
+        Err(_) => Err(format!("expected a number, got '{}'", arg)),
+    }
+}
+
+/// Reads a request parameter default from the environment (or .env file), exiting if it
+/// is set but can't be parsed.
+fn env_parameter<T: std::str::FromStr>(name: &str, default: T) -> T
+where
+    T::Err: std::fmt::Display,
+{
+    let name = env_name(name);
+    match std::env::var(&name) {
+        Ok(value) if !value.trim().is_empty() => value.trim().parse().unwrap_or_else(|e| {
+            diagnostics::error("invalid_environment_value", format!("invalid value '{}' for {}: {}", value, name, e));
+            std::process::exit(1);
+        }),
+        _ => default,
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    if let Err(e) = run().await {
+        diagnostics::error("error", e);
+        std::process::exit(1);
+    }
+}
+
+async fn run() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cli = Cli::parse();
+
+    // `ai +name input` is shorthand for `ai --model name input`
+    if let Some(second) = cli.input_after_deployment.take() {
+        let first = cli.input.take().unwrap_or_default();
+        let Some(name) = first.strip_prefix('+').filter(|name| !name.is_empty()) else {
+            diagnostics::error("usage", format!("unexpected argument '{}'. Only one input can be given; quote it if it contains spaces.", second));
+            std::process::exit(1);
+        };
+        if cli.model.is_some() {
+            diagnostics::error("usage", format!("+{} and --model can't be used together.", name));
+            std::process::exit(1);
+        }
+        cli.model = Some(name.to_string());
+        cli.input = Some(second);
+    }
+
+    let env_file = match &cli.env_file {
+        Some(path) => match dotenv::from_path(path) {
+            Ok(()) => Some(std::path::PathBuf::from(path)),
+            Err(e) => {
+                diagnostics::error("env_file", format!("could not load environment file '{}': {}", path, e));
+                std::process::exit(1);
+            }
+        },
+        None => dotenv::dotenv().ok(),
+    };
+    if let Some(prefix) = &cli.env_prefix {
+        let _ = ENV_PREFIX.set(prefix.clone());
+    }
+
+    if cli.diagnostics_json {
+        diagnostics::enable_json();
+    }
+    init_logging(cli.log_level.as_deref(), cli.verbose, cli.diagnostics_json);
+    if !cli.watch.is_empty() {
+        return watch::run(&cli.watch).await;
+    }
+    MAX_INPUT_BYTES.store(cli.max_input_bytes, Ordering::Relaxed);
+    credential_store::select(cli.store);
+    if let Some(path) = &cli.credentials_file {
+        if let Err(e) = credential_store::load_credentials_file(path) {
+            diagnostics::error("credentials_file", e);
+            std::process::exit(1);
+        }
+    }
+    if let Some(path) = env_file {
+        debug!("Loaded environment from {}", path.display());
+    }
+
+    let client = http_client(cli.keep_alive)?;
+    // Prompts given by URL are fetched up front and then read like files
+    for arg in cli.prompt.iter_mut().chain(&mut cli.messages) {
+        if remote_prompt::is_url(arg) {
+            let path = remote_prompt::fetch(&client, arg).await.unwrap_or_else(|e| {
+                diagnostics::error("prompt_url", e);
+                std::process::exit(1);
+            });
+            *arg = path.to_string_lossy().into_owned();
+        }
+    }
+    for arg in &mut cli.prompt {
+        if let Some(path) = find_include(arg, &cli.include_dir) {
+            debug!("Found --prompt '{}' at {}", arg, path.display());
+            *arg = path.to_string_lossy().into_owned();
+        }
+    }
+
+    let mut config = config::load().unwrap_or_else(|e| {
+        diagnostics::error("config", e);
+        std::process::exit(1);
+    });
+    if let Some(path) = &cli.alias_file {
+        let aliases = variables::load_file(path).unwrap_or_else(|e| {
+            diagnostics::error("alias_file", e);
+            std::process::exit(1);
+        });
+        debug!("Loaded {} deployment alias(es) from {}", aliases.len(), path);
+        config.add_aliases(aliases);
+    }
+    let model = cli.model.as_deref().map(|name| config.resolve_deployment(name));
+
+    if cli.delete_keys {
+        delete_credentials(&cli.fallback_endpoint)?;
+        println!("All credentials deleted from secure storage.");
+        return Ok(());
+    }
+
+    if let Some(template) = &cli.output_template {
+        if let Err(e) = output_template::validate(template) {
+            diagnostics::error("invalid_output_template", format!("invalid output template: {}", e));
+            std::process::exit(1);
+        }
+    }
+
+    if cli.setup {
+        return run_setup().await;
+    }
+
+    if let Some(cred_type) = &cli.set_credential {
+        return set_credential(cred_type);
+    }
+
+    if cli.test {
+        return run_connection_test(model, &config).await;
+    }
+
+    if cli.health {
+        return run_health_check(model, &config, cli.timeout.map(std::time::Duration::from_secs_f64)).await;
+    }
+
+    if let Some(names) = &cli.branch {
+        match conversation::branch(&names[0], &names[1]) {
+            Ok(count) => println!("Copied {} message(s) from '{}' into session '{}'. Continue it with --session {}.", count, names[0], names[1], names[1]),
+            Err(e) => {
+                diagnostics::error("conversation", e);
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
+    }
+
+    if cli.list_presets {
+        presets::list();
+        return Ok(());
+    }
+
+    if cli.list_personas {
+        presets::list_personas();
+        return Ok(());
+    }
+
+    if cli.show_model_info {
+        models::show(&get_deployment(model, &config)?, &config);
+        return Ok(());
+    }
+
+    if cli.capabilities || cli.refresh_capabilities {
+        return capabilities::run(model, &config, cli.refresh_capabilities).await;
+    }
+
+    if cli.dump_config {
+        return dump_config(&cli, model, &config);
+    }
+
+    let mut variables = match &cli.var_file {
+        Some(path) => variables::load_file(path).unwrap_or_else(|e| {
+            diagnostics::error("var_file", e);
+            std::process::exit(1);
+        }),
+        None => HashMap::new(),
+    };
+    variables.extend(cli.var.iter().cloned());
+    let expand_variables = |text: String| {
+        if variables.is_empty() {
+            return text;
+        }
+        let (expanded, undefined) = variables::expand(&text, &variables);
+        for name in undefined {
+            warn!(code = "undefined_variable"; "No value was given for {{{{{}}}}}; leaving it as is", name);
+        }
+        expanded
+    };
+
+    let mut batch_paths: Vec<String> = cli.batch.clone().unwrap_or_default();
+    for pattern in &cli.input_glob {
+        let matched = batch::expand_glob(pattern).unwrap_or_else(|e| {
+            diagnostics::error("input_glob", e);
+            std::process::exit(1);
+        });
+        if matched.is_empty() {
+            diagnostics::error("input_glob", format!("no files match '{}'.", pattern));
+            std::process::exit(1);
+        }
+        diagnostics::status("input_glob", format!("'{}' matched {} file(s): {}", pattern, matched.len(), matched.join(", ")));
+        batch_paths.extend(matched);
+    }
+    let is_batch = !batch_paths.is_empty();
+    let warmup_only = cli.warmup && cli.input.is_none() && cli.concat.is_none() && cli.diff_input.is_none() && !is_batch;
+
+    let mut increment = None;
+    let input = match (cli.input, &cli.concat) {
+        (_, Some(paths)) => concat_files(paths, cli.utf8_lossy),
+        (Some(path), None) if cli.since_file.is_some() => {
+            let state_path = std::path::Path::new(cli.since_file.as_deref().unwrap_or_default());
+            let read = incremental::read_since(&path, state_path, cli.utf8_lossy).unwrap_or_else(|e| {
+                diagnostics::error("since_file", e);
+                std::process::exit(1);
+            });
+            if read.text.trim().is_empty() {
+                diagnostics::status("nothing_new", format!("Nothing has been added to '{}' since the last run.", path));
+                return Ok(());
+            }
+            increment.insert(read).text.clone()
+        }
+        (Some(input_arg), None) => read_file_or_text(input_arg, cli.utf8_lossy),
+        (None, None) if cli.diff_input.is_some() => {
+            let paths = cli.diff_input.as_deref().unwrap_or_default();
+            let diff = diff_files(&paths[0], &paths[1], cli.utf8_lossy);
+            if diff.is_empty() {
+                println!("'{}' and '{}' are identical.", paths[0], paths[1]);
+                return Ok(());
+            }
+            diff
+        }
+        // Each batch input is filled in when its request is sent
+        (None, None) if is_batch || warmup_only => String::new(),
+        (None, None) => {
+            let _ = Cli::parse_from(["ai", "--help"]);
+            std::process::exit(1);
+        }
+    };
+    let prepend = cli.prepend.map(|arg| read_file_or_text(arg, cli.utf8_lossy));
+    let append = cli.append.map(|arg| read_file_or_text(arg, cli.utf8_lossy));
+    let batch_items: Vec<batch::Item> = batch_paths
+        .iter()
+        .map(|path| {
+            let contents = read_file(path, cli.utf8_lossy).unwrap_or_else(|e| {
+                diagnostics::error("read_failed", format!("could not read '{}': {}", path, e));
+                std::process::exit(1);
+            });
+            batch::Item { name: path.clone(), input: expand_variables(wrap_input(contents, prepend.clone(), append.clone())) }
+        })
+        .collect();
+    let input = expand_variables(wrap_input(input, prepend, append));
+
+    let session_path = cli.session.as_deref().map(|name| {
+        conversation::session_path(name).unwrap_or_else(|e| {
+            diagnostics::error("usage", e);
+            std::process::exit(1);
+        })
+    });
+    let history = if let Some(path) = session_path.as_ref().filter(|path| path.exists()) {
+        conversation::load(path).unwrap_or_else(|e| {
+            diagnostics::error("conversation", e);
+            std::process::exit(1);
+        })
+    } else if cli.continue_conversation {
+        let loaded = conversation::last_path()
+            .filter(|path| path.exists())
+            .map(|path| conversation::load(&path));
+        match loaded {
+            Some(Ok(messages)) => messages,
+            Some(Err(e)) => {
+                diagnostics::error("conversation", e);
+                std::process::exit(1);
+            }
+            None => {
+                diagnostics::error("no_conversation", "there is no previous conversation to continue.");
+                std::process::exit(1);
+            }
+        }
+    } else {
+        Vec::new()
+    };
+
+    if cli.prompt.len() > 1 && !cli.ensemble {
+        diagnostics::error("usage", "--prompt can only be given more than once with --ensemble.");
+        std::process::exit(1);
+    }
+    let project_prompt = match config::find_project_prompt() {
+        Some(path) if !cli.no_project_prompt => {
+            debug!("Using project prompt {}", path.display());
+            Some(read_file(&path.to_string_lossy(), cli.utf8_lossy).unwrap_or_else(|e| {
+                diagnostics::error("read_failed", format!("could not read {}: {}", path.display(), e));
+                std::process::exit(1);
+            }))
+        }
+        _ => None,
+    };
+    let with_project_prompt = |prompt: String| match &project_prompt {
+        Some(prefix) => format!("{}\n\n{}", prefix.trim_end(), prompt),
+        None => prompt,
+    };
+
+    let preset = cli.preset.as_deref().map(|name| {
+        presets::find(name).unwrap_or_else(|| {
+            diagnostics::error("unknown_preset", format!("unknown preset '{}'.", name));
+            presets::list();
+            std::process::exit(1);
+        })
+    });
+    // A --prompt goes with the project prompt, then the preset, in the --prompt-mode order
+    let with_other_prompts = |prompt: String| {
+        let others: Vec<&str> = project_prompt.iter().map(String::as_str).chain(preset.map(|preset| preset.prompt)).collect();
+        cli.prompt_mode.combine(prompt, &others)
+    };
+
+    let prompt = match &cli.prompt_git {
+        Some(spec) => Some(git_show(spec, cli.utf8_lossy).unwrap_or_else(|e| {
+            diagnostics::error("prompt_git", e);
+            std::process::exit(1);
+        })),
+        None => cli.prompt.first().cloned().map(|arg| read_file_or_text(arg, cli.utf8_lossy)),
+    };
+    let system_prompt = match (prompt, preset) {
+        (Some(prompt), _) => with_other_prompts(prompt),
+        (None, Some(preset)) => with_project_prompt(preset.prompt.to_string()),
+        (None, None) if cli.persona.is_some() => {
+            let name = cli.persona.as_deref().unwrap_or_default();
+            match presets::find_persona(name) {
+                Some(persona) => with_project_prompt(persona.prompt()),
+                None => {
+                    diagnostics::error("unknown_persona", format!("unknown persona '{}'.", name));
+                    presets::list_personas();
+                    std::process::exit(1);
+                }
+            }
+        }
+        (None, None) if cli.diff_input.is_some() => with_project_prompt(presets::find("review-changes").map(|preset| preset.prompt).unwrap_or_default().to_string()),
+        // A saved system prompt already has the project prompt from when it was saved
+        (None, None) => match history.first().filter(|m| m.role == "system") {
+            Some(saved) => saved.text(),
+            None if cli.no_default_prompt || config.require_prompt => {
+                diagnostics::error("no_prompt", "no system prompt was given; pass --prompt, --preset, or --persona.");
+                std::process::exit(1);
+            }
+            None => with_project_prompt(String::from("You are an AI assistant that helps people find information.")),
+        },
+    };
+    let system_prompt = expand_variables(system_prompt);
+
+    // Read from a secure credential store
+    let api_key = get_credential("api_key")?;
+    let endpoint = get_credential("endpoint")?;
+    let deployment = if cli.round_robin {
+        if config.round_robin.is_empty() {
+            diagnostics::error("config", "--round-robin needs a list of deployments in ai.toml, such as: round_robin = [\"east\", \"west\"]");
+            std::process::exit(1);
+        }
+        config.resolve_deployment(&config.round_robin[0])
+    } else if cli.autodetect_deployment {
+        require_credentials(&[("api_key", &api_key), ("endpoint", &endpoint)]);
+        autodetect_deployment(&endpoint, &api_key).await?
+    } else {
+        get_deployment(model, &config)?
+    };
+    require_credentials(&[("api_key", &api_key), ("endpoint", &endpoint), ("deployment", &deployment)]);
+
+    let json_path = cli.json_path.as_deref().map(|path| {
+        postprocess::parse_json_path(path).unwrap_or_else(|e| {
+            diagnostics::error("invalid_json_path", e);
+            std::process::exit(1);
+        })
+    });
+
+    let response_schema = cli.schema.as_deref().map(|path| {
+        schema::ResponseSchema::load(path).unwrap_or_else(|e| {
+            diagnostics::error("schema", e);
+            std::process::exit(1);
+        })
+    });
+
+    // Create the chat request
+    let mut messages = vec![text_message("system", system_prompt.clone())];
+    if let Some(path) = &cli.messages {
+        messages.extend(load_example_messages(path).unwrap_or_else(|e| {
+            diagnostics::error("messages", e);
+            std::process::exit(1);
+        }));
+    }
+    // This run's system prompt replaces the saved one, but later system messages, such as a
+    // --compact-history summary, stay
+    let saved_prompt = usize::from(history.first().is_some_and(|m| m.role == "system"));
+    messages.extend(history.into_iter().skip(saved_prompt));
+    let mut user_message = text_message("user", input.clone());  // Clone here so we can use input later
+    for path in &cli.image {
+        user_message.content.push(attachments::image(path).unwrap_or_else(|e| {
+            diagnostics::error("attachment", e);
+            std::process::exit(1);
+        }));
+    }
+    for path in &cli.file {
+        user_message.content.push(attachments::file(path).unwrap_or_else(|e| {
+            diagnostics::error("attachment", e);
+            std::process::exit(1);
+        }));
+    }
+    messages.push(user_message);
+
+    let parameters = config.parameters_for(&deployment);
+    let mut chat_request = ChatRequest {
+        messages,
+        temperature: cli.temperature.or(parameters.temperature).unwrap_or_else(|| env_parameter("AI_TEMPERATURE", 0.7)),
+        top_p: cli.top_p.or(parameters.top_p).unwrap_or_else(|| env_parameter("AI_TOP_P", 0.95)),
+        max_tokens: cli.max_tokens.or(parameters.max_tokens).unwrap_or_else(|| env_parameter("AI_MAX_TOKENS", 16384)),
+        stream: cli.stream,
+        stream_options: cli.stream_usage.then(|| json!({ "include_usage": true })),
+        n: cli.n,
+        response_format: match &response_schema {
+            Some(schema) => Some(schema.response_format()),
+            None if cli.strict_json || json_path.is_some() => Some(json!({ "type": "json_object" })),
+            None => None,
+        },
+        style: RequestStyle::new(&parameters),
+    };
+
+    // Asking for more output than the model can produce is rejected, so lower a --max-tokens
+    // above the limit. The name match is only a guess, so defaults are sent as they are.
+    if let Some((limits, _)) = models::lookup(&deployment, &config).filter(|_| cli.max_tokens.is_some() && !cli.no_clamp) {
+        let limit = i32::try_from(limits.max_output_tokens).unwrap_or(i32::MAX);
+        if chat_request.max_tokens > limit {
+            warn!(code = "max_tokens_clamped"; "max_tokens {} is more than '{}' can produce; using its limit of {} instead (--no-clamp sends it anyway).", chat_request.max_tokens, deployment, limit);
+            chat_request.max_tokens = limit;
+        }
+    }
+
+    // JSON mode is rejected unless the messages mention JSON
+    if chat_request.response_format.is_some() && response_schema.is_none() && !system_prompt.to_lowercase().contains("json") {
+        chat_request.messages[0].append_text("\n\nRespond only with valid JSON.");
+    }
+
+    if cli.echo_prompt && !cli.json {
+        println!("===== SYSTEM PROMPT =====\n{}\n===== INPUT =====\n{}\n===== RESPONSE =====", system_prompt.trim_end(), input.trim_end());
+    }
+
+    // Prepare API request
+    let mut deployments = if cli.round_robin {
+        config.round_robin.iter().map(|name| config.resolve_deployment(name)).collect()
+    } else {
+        vec![deployment]
+    };
+    let round_robin = cli.round_robin.then(|| round_robin::RoundRobin::load(deployments.len()));
+    deployments.extend(cli.fallback_deployment.iter().map(|name| config.resolve_deployment(name)));
+    let mut mapped_endpoints = HashMap::new();
+    for name in &deployments {
+        if let Some(mapped) = mapped_endpoint(&config, name)? {
+            mapped_endpoints.insert(name.clone(), mapped);
+        }
+    }
+    let mut endpoints = vec![endpoint];
+    endpoints.extend(cli.fallback_endpoint.iter().map(|url| url.trim_end_matches('/').to_string()));
+    let rate_limits = deployments.iter().filter_map(|name| config.rate_limit_for(name).or(cli.rate_limit).map(|limit| (name.clone(), limit))).collect();
+
+    let api = Api {
+        client,
+        endpoints,
+        api_key,
+        api_key_id: credential_info("api_key")?.keyring_id.to_string(),
+        mapped_endpoints,
+        api_versions: deployments
+            .iter()
+            .filter_map(|name| match config.parameters_for(name).api_version {
+                Some(version) => Some((name.clone(), version)),
+                // The default api-version predates stream_options, and rejects requests that have it
+                None if cli.stream_usage => Some((name.clone(), STREAM_USAGE_API_VERSION.to_string())),
+                None => None,
+            })
+            .collect(),
+        deployments,
+        round_robin,
+        style: chat_request.style.clone(),
+        retry_policy: RetryPolicy {
+            max_retries: cli.max_retries,
+            timeout_retries: cli.timeout_retries,
+            timeout: cli.timeout.map(std::time::Duration::from_secs_f64),
+            total_retries: cli.total_retries,
+            retries_used: AtomicU32::new(0),
+            budget_spent: AtomicBool::new(false),
+            rate_limiter: rate_limit::RateLimiter::new(rate_limits),
+        },
+        save_on_error: cli.save_on_error.clone(),
+        print_hash: cli.print_hash,
+        deadline: cli.max_time.map(|seconds| std::time::Instant::now() + std::time::Duration::from_secs_f64(seconds)),
+    };
+
+    if cli.warmup {
+        let ready = warmup(&api).await;
+        if warmup_only {
+            std::process::exit(if ready { 0 } else { 1 });
+        }
+    }
+
+    if let Some(limit) = cli.history_limit {
+        if cli.compact_history {
+            let compacted = compact_history(&api, &mut chat_request.messages, limit).await;
+            match compacted {
+                Ok(0) => {}
+                Ok(count) => warn!(code = "history_compacted"; "Summarized the {} oldest message(s) to keep the conversation within {} tokens", count, limit),
+                Err(e) => warn!(code = "history_compaction_failed"; "Could not summarize the conversation history ({}); dropping old messages instead", e),
+            }
+        }
+
+        let evicted = conversation::trim_history(&mut chat_request.messages, limit);
+        if evicted > 0 {
+            warn!(code = "history_trimmed"; "Dropped the {} oldest message(s) to keep the conversation within {} tokens", evicted, limit);
+        }
+    }
+
+    if cli.lint || cli.lint_strict {
+        let problems = lint::check(&chat_request.messages, &api.deployments[0], &config, &cli.include_dir, !is_batch);
+        for problem in &problems {
+            warn!(code = "lint"; "Prompt check: {}", problem);
+        }
+        if cli.lint_strict && !problems.is_empty() {
+            diagnostics::error("lint_failed", format!("the prompt check found {} problem(s); not sending the request.", problems.len()));
+            std::process::exit(1);
+        }
+    }
+
+    if is_batch {
+        let confirm_each = cli.confirm_each && !cli.yes;
+        if confirm_each && !std::io::stdin().is_terminal() {
+            diagnostics::error("usage", "--confirm-each needs a terminal to ask on; pass --yes to send every input without asking.");
+            std::process::exit(1);
+        }
+        let options = batch::Options {
+            dedup: !cli.no_dedup,
+            json_array: cli.output_json_array,
+            confirm_each,
+            concurrency: if cli.stream { cli.max_concurrent_streams.unwrap_or(cli.concurrency) } else { cli.concurrency },
+            interleave: cli.interleave,
+        };
+        return batch::run(&api, &chat_request, batch_items, &options).await;
+    }
+
+    if cli.ensemble {
+        let prompts = cli
+            .prompt
+            .iter()
+            .enumerate()
+            .map(|(index, arg)| ensemble::Prompt {
+                label: if std::path::Path::new(arg).is_file() { arg.clone() } else { format!("prompt {}", index + 1) },
+                text: expand_variables(with_other_prompts(read_file_or_text(arg.clone(), cli.utf8_lossy))),
+            })
+            .collect();
+        return ensemble::run(&api, &chat_request, prompts, &input, cli.merge, cli.concurrency).await;
+    }
+
+    if let Some(temperatures) = &cli.temperature_sweep {
+        if chat_request.style.omit_sampling {
+            diagnostics::error("usage", format!("the [parameters] entry for '{}' leaves out the temperature, so --temperature-sweep would have no effect.", api.deployments[0]));
+            std::process::exit(1);
+        }
+        return sweep::run(&api, &chat_request, temperatures, cli.output_json_array, cli.concurrency).await;
+    }
+
+    if cli.count_both {
+        let prompt_tokens = tokens::count_prompt_tokens(&chat_request.messages, &api.deployments[0]);
+        let max_tokens = chat_request.max_tokens.max(0) as usize;
+        diagnostics::status("prompt_tokens", format!("Prompt tokens:         {}", prompt_tokens));
+        diagnostics::status("max_completion_tokens", format!("Max completion tokens: {}", max_tokens));
+        diagnostics::status("worst_case_tokens", format!("Worst case total:      {}", prompt_tokens + max_tokens));
+    }
+
+    if cli.cache_prompt {
+        let prompt_tokens = tokens::count_prompt_tokens(&chat_request.messages, &api.deployments[0]);
+        if prompt_tokens < MIN_CACHED_PROMPT_TOKENS {
+            warn!(code = "prompt_not_cacheable"; "The prompt is about {} tokens; prompts under {} tokens aren't cached", prompt_tokens, MIN_CACHED_PROMPT_TOKENS);
+        }
+    }
+
+    let mut sink = sink::Sink::new(cli.output.as_deref(), !cli.output_only, cli.output_encoding, cli.truncate_output).unwrap_or_else(|e| {
+        diagnostics::error("output_file", e);
+        std::process::exit(1);
+    });
+    let newline = if cli.no_trailing_newline { "" } else { "\n" };
+
+    // With --debug-dual, the body of the last response, for writing alongside what was printed
+    let mut debug_body = None;
+    let mut empty_retries = 0;
+    let mut short_retries = 0;
+    let mut language_retries = 0;
+    api.print_hash(&chat_request, None);
+    let (keyring_id, request_id, streamed, response_json) = loop {
+        let request_start = std::time::Instant::now();
+        let mut served = api.send(&chat_request).await?;
+
+        // If the service doesn't support structured output, ask for the schema in the prompt
+        // instead and rely on validating the response locally
+        if let Some(schema) = &response_schema {
+            let status = served.response.status();
+            if status == reqwest::StatusCode::BAD_REQUEST {
+                let request_id = served.response.request_id();
+                let response_json = read_json(served.response).await.unwrap_or_else(|e| {
+                    diagnostics::error("invalid_response", e);
+                    std::process::exit(1);
+                });
+                if !schema::is_unsupported_response_format(status, &response_json) {
+                    print_error_response(&response_json, &input, &served.keyring_id, request_id.as_deref())?;
+                    if cli.explain_error {
+                        explain_error(&api, &response_json).await;
+                    }
+                    return Ok(());
+                }
+
+                warn!(code = "schema_unsupported"; "This deployment doesn't support JSON schema output; including the schema in the system prompt instead");
+                chat_request.response_format = None;
+                chat_request.messages[0].append_text(&format!("\n\n{}", schema.instructions()));
+                served = api.send(&chat_request).await?;
+            }
+        }
+        let response = served.response;
+        let status = response.status();
+        let request_id = response.request_id();
+        if let Some(id) = &request_id {
+            if cli.show_request_id {
+                diagnostics::status("request_id", format!("Request ID: {}", id));
+            } else {
+                info!("Request ID: {}", id);
+            }
+        }
+
+        if cli.raw_response {
+            let mut stdout = std::io::stdout();
+            stdout.write_all(&response.body().await?.bytes)?;
+            stdout.flush()?;
+            if !status.is_success() {
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+
+        // Streamed output is printed as it arrives, so only errors are left to print below
+        let streamed = cli.stream && status.is_success();
+        let response_json: Value = match response {
+            Reply::Arriving(response) if streamed => {
+                let flush_every = cli.flush_every.unwrap_or(if std::io::stdout().is_terminal() { 1 } else { 0 });
+                let idle_timeout = cli.stream_idle_timeout.map(std::time::Duration::from_secs_f64);
+                let suffix = format!("{}{}", cli.response_suffix, newline);
+                match streaming::read_stream(response, flush_every, &mut sink, (&cli.response_prefix, &suffix), idle_timeout).await {
+                    Ok(response_json) => response_json,
+                    Err(e) if e.is::<streaming::Stalled>() => {
+                        diagnostics::error("stream_stalled", format!("{}; the output above is incomplete.", e));
+                        std::process::exit(EXIT_TIMEOUT);
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            response => {
+                let body = response.body().await.unwrap_or_else(|e| {
+                    diagnostics::error("invalid_response", e);
+                    std::process::exit(1);
+                });
+                let parsed = body.json();
+                if let Some(path) = &cli.debug_dual {
+                    // Written now in case the run stops before printing, and again once it prints
+                    if let Err(e) = write_debug_dual(path, &body, parsed.as_ref().ok(), None) {
+                        warn!(code = "debug_dual"; "could not write {}: {}", path, e);
+                    }
+                    debug_body = Some(body);
+                }
+                parsed.unwrap_or_else(|e| {
+                    diagnostics::error("invalid_response", e);
+                    std::process::exit(1);
+                })
+            }
+        };
+
+        if let Some(path) = &cli.audit_log {
+            let entry = audit::Entry {
+                timestamp: chrono::Local::now().to_rfc3339(),
+                endpoint: &served.endpoint,
+                deployment: &served.deployment,
+                status: status.as_u16(),
+                duration_ms: request_start.elapsed().as_millis(),
+                usage: &response_json["usage"],
+                tags: &cli.tag.iter().cloned().collect(),
+            };
+            if let Err(e) = audit::append(path, &entry) {
+                warn!(code = "audit_log"; "{}", e);
+            }
+        }
+
+        // An empty response often comes back again if the request is resent unchanged,
+        // so --escalate-temp raises the temperature a little on each retry
+        let empty = response_json["choices"][0]["message"]["content"].as_str().is_some_and(|c| c.trim().is_empty())
+            && refusal_reason(&response_json["choices"][0]).is_none();
+        if empty && empty_retries < cli.retry_empty {
+            empty_retries += 1;
+            if cli.escalate_temp {
+                // Rounded so the request doesn't carry float noise like 1.3000001
+                let raised = ((chat_request.temperature + ESCALATE_TEMP_STEP) * 100.0).round() / 100.0;
+                chat_request.temperature = raised.min(ESCALATE_TEMP_MAX).max(chat_request.temperature);
+            }
+            warn!(code = "empty_response"; "Empty response; retrying at temperature {} (retry {} of {})", chat_request.temperature, empty_retries, cli.retry_empty);
+            continue;
+        }
+
+        if let (Some(min_tokens), Some(content)) = (cli.min_tokens, response_json["choices"][0]["message"]["content"].as_str()) {
+            let completion_tokens = response_json["usage"]["completion_tokens"]
+                .as_u64()
+                .map(|tokens| tokens as usize)
+                .unwrap_or_else(|| tokens::count_tokens(content, &served.deployment));
+            if completion_tokens < min_tokens {
+                if short_retries < MAX_ELABORATE_RETRIES {
+                    short_retries += 1;
+                    if short_retries == 1 {
+                        if let Some(user_message) = chat_request.messages.last_mut() {
+                            user_message.append_text(ELABORATE_INSTRUCTION);
+                        }
+                    }
+                    warn!(code = "short_response"; "The response was only {} tokens; asking for more detail (retry {} of {})", completion_tokens, short_retries, MAX_ELABORATE_RETRIES);
+                    continue;
+                }
+                warn!(code = "short_response"; "The response is still only {} tokens, below --min-tokens {}", completion_tokens, min_tokens);
+            }
+        }
+        if let (Some(expected), Some(content)) = (cli.response_language, response_json["choices"][0]["message"]["content"].as_str()) {
+            match postprocess::detect_language(content) {
+                Some(detected) if detected != expected => {
+                    if cli.retry_on_language_mismatch && language_retries < MAX_LANGUAGE_RETRIES {
+                        language_retries += 1;
+                        if language_retries == 1 {
+                            if let Some(user_message) = chat_request.messages.last_mut() {
+                                user_message.append_text(&format!("\n\nRespond only in {}.", expected.eng_name()));
+                            }
+                        }
+                        warn!(code = "language_mismatch"; "The response appears to be in {} rather than {}; asking again (retry {} of {})", detected.eng_name(), expected.eng_name(), language_retries, MAX_LANGUAGE_RETRIES);
+                        continue;
+                    }
+                    warn!(code = "language_mismatch"; "The response appears to be in {} rather than {}", detected.eng_name(), expected.eng_name());
+                }
+                Some(_) => {}
+                None => debug!("The response's language couldn't be detected reliably; not checking it"),
+            }
+        }
+        break (served.keyring_id, request_id, streamed, response_json);
+    };
+    let write_debug = |response_json: &Value, printed: &str| {
+        if let (Some(path), Some(body)) = (&cli.debug_dual, &debug_body) {
+            if let Err(e) = write_debug_dual(path, body, Some(response_json), Some(printed)) {
+                warn!(code = "debug_dual"; "could not write {}: {}", path, e);
+            }
+        }
+    };
+
+    let mut response_json = response_json;
+    if response_json["choices"][0]["finish_reason"] == "length" {
+        if cli.auto_continue {
+            continue_truncated(&api, &chat_request, &mut response_json).await?;
+        }
+        if response_json["choices"][0]["finish_reason"] == "length" {
+            warn!(code = "truncated"; "Response truncated at max_tokens ({}).", chat_request.max_tokens);
+        }
+    }
+    api.report_retry_budget();
+
+    if cli.print_finish_reason && !cli.json {
+        if let Some(reason) = response_json["choices"][0]["finish_reason"].as_str() {
+            diagnostics::status("finish_reason", format!("Finish reason: {}", reason));
+        }
+    }
+    if cli.stream_usage {
+        report_stream_usage(&response_json["usage"]);
+    }
+    if cli.cache_prompt {
+        report_cache_usage(&response_json["usage"]);
+    }
+
+    if let Some(choices) = response_json["choices"].as_array() {
+        let refusal = refusal_reason(&choices[0]);
+        if cli.fail_on_refusal {
+            if let Some(reason) = &refusal {
+                diagnostics::error("refusal", format!("the model declined to respond: {}", reason));
+                std::process::exit(1);
+            }
+        }
+
+        let clean = |message: &str| {
+            let mut content = message.to_string();
+            if cli.strip_thinking {
+                let (stripped, thoughts) = postprocess::strip_thinking(&content, &cli.thinking_open, &cli.thinking_close);
+                for thought in thoughts {
+                    info!("Stripped reasoning:\n{}", thought);
+                }
+                content = stripped;
+            }
+            if cli.no_filler {
+                let (stripped, filler) = postprocess::strip_filler(&content);
+                for paragraph in filler {
+                    info!("Stripped filler: {}", paragraph);
+                }
+                content = stripped;
+            }
+            content
+        };
+
+        if let Some(dir) = &cli.output_split {
+            let created = write_candidates(dir, choices, clean).unwrap_or_else(|e| {
+                diagnostics::error("output_split", e);
+                std::process::exit(1);
+            });
+            diagnostics::status("output_split", format!("Wrote {} candidate file(s): {}", created.len(), created.join(", ")));
+        }
+
+        if let Some(message) = choices[0]["message"]["content"].as_str() {
+            // Some reasoning models return their reasoning separately from the content
+            if let Some(reasoning) = choices[0]["message"]["reasoning_content"].as_str() {
+                info!("Model reasoning:\n{}", reasoning);
+            }
+
+            let content = clean(message);
+
+            let content = if cli.repair_json {
+                match postprocess::repair_json(&content) {
+                    Ok(Some(repaired)) => {
+                        diagnostics::status("repair_json", "The response was not strict JSON; repaired it.");
+                        repaired
+                    }
+                    Ok(None) => {
+                        info!("The response was already strict JSON; no repair needed");
+                        content
+                    }
+                    Err(e) => {
+                        warn!(code = "repair_json"; "The response could not be repaired as JSON: {}", e);
+                        content
+                    }
+                }
+            } else {
+                content
+            };
+
+            let content = if cli.strict_json {
+                let style = match (cli.json_pretty, cli.json_minify) {
+                    (true, _) => postprocess::JsonStyle::Pretty,
+                    (_, true) => postprocess::JsonStyle::Minified,
+                    _ => postprocess::JsonStyle::Unchanged,
+                };
+                postprocess::check_json(&content, style).unwrap_or_else(|e| {
+                    diagnostics::error("invalid_json", format!("the response is not valid JSON: {}", e));
+                    if !streamed {
+                        diagnostics::status("response", format!("\nResponse:\n{}", content));
+                    }
+                    std::process::exit(1);
+                })
+            } else {
+                content
+            };
+            let content = match &json_path {
+                Some(path) => postprocess::extract_json_path(&content, path).unwrap_or_else(|e| {
+                    diagnostics::error("json_path", e);
+                    diagnostics::status("response", format!("\nResponse:\n{}", content));
+                    std::process::exit(1);
+                }),
+                None => content,
+            };
+            let message = content.as_str();
+
+            if let Some(schema) = &response_schema {
+                if let Err(errors) = schema.validate(message) {
+                    diagnostics::error("schema_mismatch", format!("the response does not match the schema:\n    {}", errors.join("\n    ")));
+                    if !streamed {
+                        diagnostics::status("response", format!("\nResponse:\n{}", message));
+                    }
+                    std::process::exit(1);
+                }
+            }
+
+            let output = if cli.json {
+                let mut output = json!({
+                    "content": message,
+                    "model": response_json["model"],
+                    "finish_reason": choices[0]["finish_reason"],
+                    "usage": response_json["usage"],
+                });
+                if cli.echo_prompt {
+                    output["system_prompt"] = json!(system_prompt);
+                    output["input"] = json!(input);
+                }
+                Some(serde_json::to_string_pretty(&output)?)
+            } else {
+                match &cli.output_template {
+                    Some(template) => Some(output_template::render(template, &response_json, message)?),
+                    None if !streamed => Some(message.to_string()),
+                    None => None,
+                }
+            };
+
+            write_debug(&response_json, output.as_deref().unwrap_or(message));
+            match (output, &cli.pipe_to) {
+                (Some(output), Some(command)) => {
+                    write!(sink, "{}", cli.response_prefix)?;
+                    pipe_through(command, &output, &mut sink)?;
+                    if !cli.response_suffix.is_empty() {
+                        write!(sink, "{}{}", cli.response_suffix, newline)?;
+                    }
+                    sink.flush()?;
+                }
+                (Some(output), None) => {
+                    write!(sink, "{}{}{}{}", cli.response_prefix, output, cli.response_suffix, newline)?;
+                    sink.flush()?;
+                }
+                (None, _) => {}
+            }
+
+            if let Some(increment) = &increment {
+                if let Err(e) = increment.commit() {
+                    warn!(code = "since_file"; "{}", e);
+                }
+            }
+
+            // Remember this exchange so the next run can --continue it
+            let mut messages = chat_request.messages.clone();
+            messages.push(text_message("assistant", message.to_string()));
+            for path in conversation::last_path().iter().chain(&session_path) {
+                if let Err(e) = conversation::save(path, &messages) {
+                    warn!(code = "conversation"; "{}", e);
+                }
+            }
+
+            enforce_assertions(&cli.assert_contains, &cli.assert_matches, message);
+        } else if let Some(reason) = refusal {
+            write_debug(&response_json, &reason);
+            write!(sink, "{}{}", reason, newline)?;
+            sink.flush()?;
+            enforce_assertions(&cli.assert_contains, &cli.assert_matches, "");
+        } else {
+            print_error_response(&response_json, &input, &keyring_id, request_id.as_deref())?;
+            enforce_assertions(&cli.assert_contains, &cli.assert_matches, "");
+        }
+    } else {
+        print_error_response(&response_json, &input, &keyring_id, request_id.as_deref())?;
+        if cli.explain_error {
+            explain_error(&api, &response_json).await;
+        }
+        enforce_assertions(&cli.assert_contains, &cli.assert_matches, "");
+    }
+
+    Ok(())
+}
+
+/// Checks the response against every --assert-contains and --assert-matches, and exits
+/// with an error if any fail. A response without content is checked as empty text.
+fn enforce_assertions(contains: &[String], matches: &[regex::Regex], content: &str) {
+    let failures = postprocess::check_assertions(content, contains, matches);
+    if failures.is_empty() {
+        return;
+    }
+    for failure in &failures {
+        diagnostics::error("assertion_failed", failure);
+    }
+    let total = contains.len() + matches.len();
+    diagnostics::error("assertion_failed", format!("{} of {} assertion(s) failed.", failures.len(), total));
+    std::process::exit(1);
+}
+
+/// Asks the model to explain an error response in plain language. This is a single
+/// attempt: if it fails too, that is only reported, and never explained in turn.
+async fn explain_error(api: &Api, response_json: &Value) {
+    if response_json.get("error").is_none() {
+        return;
+    }
+
+    let request = ChatRequest {
+        messages: vec![
+            text_message("system", "You explain Azure OpenAI API errors. Given an error response, explain in plain language what went wrong and what the user can do to fix it. Be brief.".to_string()),
+            text_message("user", serde_json::to_string_pretty(response_json).unwrap_or_default()),
+        ],
+        temperature: 0.3,
+        top_p: 0.95,
+        max_tokens: 512,
+        stream: false,
+        stream_options: None,
+        n: None,
+        response_format: None,
+        style: api.style.clone(),
+    };
+    match api.complete(&request).await {
+        Ok(explanation) => println!("\nExplanation:\n{}", explanation.trim()),
+        Err(e) => warn!(code = "explain_failed"; "Could not explain the error: {}", e),
+    }
+}
+
+/// Runs `command` through the platform shell with `text` on its stdin, writing what it
+/// writes to stdout to `out`. A non-zero exit from the command becomes this tool's exit code.
+fn pipe_through(command: &str, text: &str, out: &mut sink::Sink) -> Result<(), Box<dyn std::error::Error>> {
+    let (shell, flag) = if cfg!(windows) { ("cmd", "/C") } else { ("sh", "-c") };
+    let mut child = std::process::Command::new(shell)
+        .args([flag, command])
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("could not run '{}': {}", command, e))?;
+
+    // Write from another thread so a command that emits output before reading all of
+    // its input can't deadlock against us
+    let mut stdin = child.stdin.take().ok_or("could not open stdin for the command")?;
+    let input = format!("{}\n", text);
+    let writer = std::thread::spawn(move || stdin.write_all(input.as_bytes()));
+
+    let output = child.wait_with_output()?;
+    if let Err(e) = writer.join().map_err(|_| "the command's stdin writer panicked")? {
+        // A command that exits without reading all of its input is fine
+        if e.kind() != ErrorKind::BrokenPipe {
+            return Err(e.into());
+        }
+    }
+
+    out.write_all(&output.stdout)?;
+    out.flush()?;
+    if !output.status.success() {
+        diagnostics::error("command_failed", format!("'{}' exited with {}", command, output.status));
+        std::process::exit(output.status.code().unwrap_or(1));
+    }
+    Ok(())
+}
+
+/// Describes why the model declined to answer, if it did: either an explicit `refusal`
+/// from the API or a response stopped by the content filter.
+fn refusal_reason(choice: &Value) -> Option<String> {
+    if let Some(refusal) = choice["message"]["refusal"].as_str() {
+        return Some(refusal.to_string());
+    }
+    if choice["finish_reason"].as_str() == Some("content_filter") {
+        return Some("the response was blocked by the content filter".to_string());
+    }
+    None
+}
+
+/// Rough estimate: 1 token ≈ 4 chars in English
+fn estimate_tokens(text: &str) -> usize {
+    text.len() / 4
+}
+
+fn text_message(role: &str, text: String) -> Message {
+    Message {
+        role: role.to_string(),
+        content: vec![Content::Text { text }],
+    }
+}
+
+/// The client shared by every request of a run, so they can reuse its pooled connections.
+fn http_client(keep_alive: f64) -> Result<reqwest::Client, reqwest::Error> {
+    let builder = reqwest::Client::builder();
+    let builder = if keep_alive > 0.0 {
+        builder.pool_idle_timeout(std::time::Duration::from_secs_f64(keep_alive))
+    } else {
+        builder.pool_max_idle_per_host(0)
+    };
+    builder.build()
+}
+
+/// The `api-version` for deployments whose `[parameters]` entry doesn't name one
+const DEFAULT_API_VERSION: &str = "2024-02-15-preview";
+
+/// The `api-version` --stream-usage requests are sent with when `[parameters]` doesn't name
+/// one, since `stream_options` needs a newer version than the default
+const STREAM_USAGE_API_VERSION: &str = "2024-10-21";
+
+fn chat_completions_url(endpoint: &str, deployment: &str, api_version: Option<&str>) -> String {
+    format!("{}/openai/deployments/{}/chat/completions?api-version={}", endpoint, deployment, api_version.unwrap_or(DEFAULT_API_VERSION))
+}
+
+async fn send_chat_request(client: &reqwest::Client, url: &str, api_key: &str, chat_request: &ChatRequest, timeout: Option<std::time::Duration>) -> Result<reqwest::Response, reqwest::Error> {
+    info!("Sending request to {}", url);
+    if log::log_enabled!(log::Level::Trace) {
+        trace!("Request body: {}", chat_request.body());
+    }
+
+    ATTEMPTS.fetch_add(1, Ordering::Relaxed);
+    let start = std::time::Instant::now();
+    let mut request = client
+        .post(url)
+        .header("api-key", api_key)
+        .json(&chat_request.body());
+    if let Some(timeout) = timeout {
+        request = request.timeout(timeout);
+    }
+    let result = request.send().await;
+
+    match &result {
+        Ok(response) => info!("Received HTTP {} after {} ms", response.status().as_u16(), start.elapsed().as_millis()),
+        Err(e) => info!("Request failed after {} ms: {}", start.elapsed().as_millis(), e),
+    }
+    result
+}
+
+/// Writes the --debug-dual file: the raw response body, then what was parsed from it and,
+/// once the response has been printed, the text that was printed.
+fn write_debug_dual(path: &str, body: &Body, parsed: Option<&Value>, printed: Option<&str>) -> std::io::Result<()> {
+    let mut file = std::io::BufWriter::new(fs::File::create(path)?);
+    writeln!(file, "===== RAW RESPONSE (HTTP {}, {} bytes) =====", body.status.as_u16(), body.bytes.len())?;
+    file.write_all(&body.bytes)?;
+    writeln!(file, "\n\n===== PARSED =====")?;
+    match parsed {
+        Some(response_json) => {
+            let choice = &response_json["choices"][0];
+            let parsed = json!({
+                "content": choice["message"]["content"],
+                "printed": printed,
+                "finish_reason": choice["finish_reason"],
+                "choices": response_json["choices"].as_array().map_or(0, Vec::len),
+                "model": response_json["model"],
+                "usage": response_json["usage"],
+            });
+            writeln!(file, "{}", serde_json::to_string_pretty(&parsed).unwrap_or_default())?;
+        }
+        None => writeln!(file, "(the body is not valid JSON)")?,
+    }
+    file.flush()
+}
+
+/// How many bytes of an unparseable response body to show
+const BODY_PREVIEW_BYTES: usize = 512;
+
+/// A response body as it arrived, with the headers needed to explain it if it isn't JSON.
+struct Body {
+    status: reqwest::StatusCode,
+    request_id: Option<String>,
+    content_type: String,
+    content_encoding: String,
+    bytes: Vec<u8>,
+}
+
+impl Body {
+    async fn read(response: reqwest::Response) -> Result<Body, reqwest::Error> {
+        let status = response.status();
+        let request_id = request_id(&response);
+        let header = |name: reqwest::header::HeaderName| {
+            response.headers().get(name).map(|v| String::from_utf8_lossy(v.as_bytes()).into_owned()).unwrap_or_else(|| "(none)".to_string())
+        };
+        let content_type = header(reqwest::header::CONTENT_TYPE);
+        let content_encoding = header(reqwest::header::CONTENT_ENCODING);
+        let bytes = response.bytes().await?.to_vec();
+        Ok(Body { status, request_id, content_type, content_encoding, bytes })
+    }
+
+    /// Parses the body as JSON. Proxies and gateways sometimes answer with HTML error
+    /// pages or mis-encoded bodies, so when parsing fails the error describes what actually
+    /// came back instead of just saying it couldn't be decoded.
+    fn json(&self) -> Result<Value, Box<dyn std::error::Error>> {
+        let body = &self.bytes;
+        let error = match serde_json::from_slice(body) {
+            Ok(value) => return Ok(value),
+            Err(e) => e,
+        };
+
+        let preview = &body[..body.len().min(BODY_PREVIEW_BYTES)];
+        let utf8 = std::str::from_utf8(body).is_ok();
+        let mut message = format!(
+            "the response body is not valid JSON ({})\n    HTTP status:      {}\n    Content-Type:     {}\n    Content-Encoding: {}\n    Body:             {} bytes{}\n    Preview:          {}",
+            error,
+            self.status,
+            self.content_type,
+            self.content_encoding,
+            body.len(),
+            if utf8 { "" } else { ", not valid UTF-8" },
+            String::from_utf8_lossy(preview).trim(),
+        );
+        if !utf8 {
+            let hex: Vec<String> = preview.iter().take(64).map(|b| format!("{:02x}", b)).collect();
+            message.push_str(&format!("\n    Hex:              {}", hex.join(" ")));
+        }
+        Err(message.into())
+    }
+}
+
+async fn read_json(reply: Reply) -> Result<Value, Box<dyn std::error::Error>> {
+    reply.body().await?.json()
+}
+
+/// A response as `send` hands it back: usually still arriving, but already read when
+/// --save-on-error needed its body for a report.
+enum Reply {
+    Arriving(reqwest::Response),
+    Read(Body),
+}
+
+impl Reply {
+    fn status(&self) -> reqwest::StatusCode {
+        match self {
+            Reply::Arriving(response) => response.status(),
+            Reply::Read(body) => body.status,
+        }
+    }
+
+    fn request_id(&self) -> Option<String> {
+        match self {
+            Reply::Arriving(response) => request_id(response),
+            Reply::Read(body) => body.request_id.clone(),
+        }
+    }
+
+    async fn body(self) -> Result<Body, reqwest::Error> {
+        match self {
+            Reply::Arriving(response) => Body::read(response).await,
+            Reply::Read(body) => Ok(body),
+        }
+    }
+}
+
+/// How requests are paced, and how failed ones are retried.
+struct RetryPolicy {
+    /// Retries for throttled (429) and server error (5xx) responses
+    max_retries: u32,
+    /// Retries for requests that time out. These are budgeted separately because a timeout
+    /// often means the request is too large to finish, which retrying won't fix.
+    timeout_retries: u32,
+    /// Time limit for each attempt
+    timeout: Option<std::time::Duration>,
+    /// Retries allowed across every request of the run, from --total-retries
+    total_retries: Option<u32>,
+    retries_used: AtomicU32,
+    budget_spent: AtomicBool,
+    /// Keeps each deployment within its [rate_limits] entry or --rate-limit
+    rate_limiter: rate_limit::RateLimiter,
+}
+
+impl RetryPolicy {
+    /// Takes one retry from the --total-retries budget, returning false once it is spent.
+    fn take_retry(&self) -> bool {
+        let allowed = self
+            .retries_used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| match self.total_retries {
+                Some(total) if used >= total => None,
+                _ => Some(used + 1),
+            })
+            .is_ok();
+        if !allowed && !self.budget_spent.swap(true, Ordering::Relaxed) {
+            warn!(code = "retry_budget_exhausted"; "All {} retries allowed by --total-retries are used up; failed requests won't be retried", self.total_retries.unwrap_or_default());
+        }
+        allowed
+    }
+
+    /// How much of the --total-retries budget was used, if there is one.
+    fn budget_report(&self) -> Option<String> {
+        let total = self.total_retries?;
+        let used = self.retries_used.load(Ordering::Relaxed);
+        Some(format!("Used {} of {} retries allowed by --total-retries.", used, total))
+    }
+}
+
+struct ServedResponse {
+    response: Reply,
+    endpoint: String,
+    deployment: String,
+    /// Keyring entry holding the API key that was used, which is cleared if it was rejected
+    keyring_id: String,
+}
+
+/// Everything needed to send a chat request: where to send it, with which key, and how
+/// to retry and fail over.
+struct Api {
+    client: reqwest::Client,
+    endpoints: Vec<String>,
+    api_key: String,
+    /// The credential entry `api_key` is stored under, cleared if the service rejects it
+    api_key_id: String,
+    /// The `[endpoints]` entries of the deployments that have one, which are sent there
+    /// instead of to the first endpoint
+    mapped_endpoints: HashMap<String, MappedEndpoint>,
+    deployments: Vec<String>,
+    /// With --round-robin, which deployment each request goes to first
+    round_robin: Option<round_robin::RoundRobin>,
+    /// The api-version of each deployment whose `[parameters]` entry names one
+    api_versions: HashMap<String, String>,
+    /// How the deployment's `[parameters]` say requests should be written, for requests
+    /// the tool makes on its own such as summaries
+    style: RequestStyle,
+    retry_policy: RetryPolicy,
+    /// With --save-on-error, the directory failed requests are reported to
+    save_on_error: Option<String>,
+    /// With --print-hash, print each request's hash before sending it
+    print_hash: bool,
+    /// When --max-time runs out
+    deadline: Option<std::time::Instant>,
+}
+
+impl Api {
+    /// With --total-retries, reports how much of the budget the run used.
+    fn report_retry_budget(&self) {
+        if let Some(report) = self.retry_policy.budget_report() {
+            diagnostics::status("retry_budget", report);
+        }
+    }
+
+    /// With --print-hash, prints the request's hash, labeled when a run sends several.
+    fn print_hash(&self, chat_request: &ChatRequest, label: Option<&str>) {
+        if !self.print_hash {
+            return;
+        }
+        match label {
+            Some(label) => diagnostics::status("request_hash", format!("Request hash ({}): {}", label, chat_request.hash())),
+            None => diagnostics::status("request_hash", format!("Request hash: {}", chat_request.hash())),
+        }
+    }
+
+    /// Sends the request to each endpoint in turn until one is reachable and doesn't return a
+    /// server error, and within each endpoint to each deployment in turn while throttled.
+    /// The first endpoint uses `api_key`, except for deployments with an `[endpoints]` entry,
+    /// which are sent there with its key; fallback endpoints use their own stored keys.
+    /// Deployments in `api_versions` are sent that api-version rather than the default.
+    async fn send_with_failover(&self, deployments: &[String], chat_request: &ChatRequest) -> Result<ServedResponse, Box<dyn std::error::Error>> {
+        for (index, endpoint) in self.endpoints.iter().enumerate() {
+            let fallback_key = if index == 0 {
+                None
+            } else {
+                let keyring_id = endpoint_key_id(endpoint);
+                let prompt = format!("Please enter the API key for {} (input will be hidden): ", endpoint);
+                Some((get_keyring_credential("api_key", &keyring_id, &prompt)?, keyring_id))
+            };
+            let next_endpoint = self.endpoints.get(index + 1);
+
+            let mut deployments = deployments.iter().peekable();
+            let result = loop {
+                let deployment = deployments.next().expect("at least one deployment");
+                let (endpoint, api_key, keyring_id) = match &fallback_key {
+                    Some((api_key, keyring_id)) => (endpoint.as_str(), api_key.as_str(), keyring_id.as_str()),
+                    None => self.first_endpoint(deployment),
+                };
+                let url = chat_completions_url(endpoint, deployment, self.api_versions.get(deployment).map(String::as_str));
+                let response = match send_with_retries(&self.client, (&url, deployment), api_key, chat_request, &self.retry_policy).await {
+                    Ok(response) => response,
+                    Err(e) => break Err(e),
+                };
+
+                match deployments.peek() {
+                    Some(next) if response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS => {
+                        warn!(code = "deployment_failover"; "Deployment '{}' is still throttled; failing over to '{}'", deployment, next);
+                    }
+                    _ => {
+                        info!("Response served by deployment '{}' at {}", deployment, endpoint);
+                        break Ok((response, deployment, endpoint, keyring_id));
+                    }
+                }
+            };
+
+            match (result, next_endpoint) {
+                (Err(e), Some(next)) if e.is_connect() || e.is_timeout() => {
+                    warn!(code = "endpoint_failover"; "Could not reach {} ({}); failing over to {}", endpoint, e, next);
+                }
+                (Ok((response, ..)), Some(next)) if response.status().is_server_error() => {
+                    warn!(code = "endpoint_failover"; "{} returned HTTP {}; failing over to {}", endpoint, response.status().as_u16(), next);
+                }
+                (Ok((response, deployment, endpoint, keyring_id)), _) => {
+                    return Ok(ServedResponse {
+                        response: Reply::Arriving(response),
+                        endpoint: endpoint.to_string(),
+                        deployment: deployment.clone(),
+                        keyring_id: keyring_id.to_string(),
+                    });
+                }
+                (Err(e), _) => return Err(e.into()),
+            }
+        }
+        unreachable!("the last endpoint always returns")
+    }
+
+    /// Where a request to `deployment` goes at the first endpoint, with the key to send and
+    /// the credential entry it is stored under.
+    fn first_endpoint<'a>(&'a self, deployment: &str) -> (&'a str, &'a str, &'a str) {
+        match self.mapped_endpoints.get(deployment) {
+            Some(mapped) => (&mapped.endpoint, &mapped.api_key, &mapped.keyring_id),
+            None => (&self.endpoints[0], &self.api_key, &self.api_key_id),
+        }
+    }
+
+    async fn send(&self, chat_request: &ChatRequest) -> Result<ServedResponse, Box<dyn std::error::Error>> {
+        let deployments = match &self.round_robin {
+            Some(round_robin) => round_robin.next_order(&self.deployments),
+            None => self.deployments.clone(),
+        };
+        let start = std::time::Instant::now();
+        let result = finish_by(self.deadline, self.send_with_failover(&deployments, chat_request)).await;
+        match (&self.save_on_error, result) {
+            (Some(dir), Err(e)) => {
+                let failure = error_report::Failure {
+                    endpoint: None,
+                    deployment: None,
+                    status: None,
+                    headers: None,
+                    body: None,
+                    error: Some(e.to_string()),
+                    duration: start.elapsed(),
+                };
+                save_error_report(dir, chat_request, &failure);
+                Err(e)
+            }
+            (Some(dir), Ok(ServedResponse { response: Reply::Arriving(response), endpoint, deployment, keyring_id })) if !response.status().is_success() => {
+                // The body is read for the report, so hand it back already read
+                let headers = response.headers().clone();
+                let body = Body::read(response).await?;
+                let failure = error_report::Failure {
+                    endpoint: Some(&endpoint),
+                    deployment: Some(&deployment),
+                    status: Some(body.status),
+                    headers: Some(&headers),
+                    body: Some(&body.bytes),
+                    error: None,
+                    duration: start.elapsed(),
+                };
+                save_error_report(dir, chat_request, &failure);
+                Ok(ServedResponse { response: Reply::Read(body), endpoint, deployment, keyring_id })
+            }
+            (_, result) => result,
+        }
+    }
+
+    /// Sends a request whose answer is used by the tool itself rather than printed,
+    /// returning the response content.
+    async fn complete(&self, chat_request: &ChatRequest) -> Result<String, Box<dyn std::error::Error>> {
+        let response_json = self.complete_json(chat_request).await?;
+        Ok(response_json["choices"][0]["message"]["content"].as_str().unwrap_or_default().to_string())
+    }
+
+    /// Like `complete`, but returns the whole response so callers can read the usage too.
+    async fn complete_json(&self, chat_request: &ChatRequest) -> Result<Value, Box<dyn std::error::Error>> {
+        let served = self.send(chat_request).await?;
+        let status = served.response.status();
+        let request_id = served.response.request_id();
+        let response_json = read_json(served.response).await?;
+        match response_json["choices"][0]["message"]["content"].as_str() {
+            Some(_) if status.is_success() => Ok(response_json),
+            _ => Err(response_error(status, request_id.as_deref(), &response_json)),
+        }
+    }
+
+    /// Like `complete_json` for a streaming request, writing the text to `out` as it arrives.
+    async fn complete_streamed(&self, chat_request: &ChatRequest, out: &mut impl Write) -> Result<Value, Box<dyn std::error::Error>> {
+        let served = self.send(chat_request).await?;
+        match served.response {
+            Reply::Arriving(response) if response.status().is_success() => streaming::read_stream(response, 0, out, ("", "\n"), None).await,
+            reply => {
+                let status = reply.status();
+                let request_id = reply.request_id();
+                let response_json = read_json(reply).await?;
+                Err(response_error(status, request_id.as_deref(), &response_json))
+            }
+        }
+    }
+}
+
+/// The error for a failed request of the tool's own, such as a --batch input.
+fn response_error(status: reqwest::StatusCode, request_id: Option<&str>, response_json: &Value) -> Box<dyn std::error::Error> {
+    let message = response_json["error"]["message"].as_str().unwrap_or("no content in the response");
+    match request_id {
+        Some(id) => format!("HTTP {}: {} (request ID {})", status.as_u16(), message, id).into(),
+        None => format!("HTTP {}: {}", status.as_u16(), message).into(),
+    }
+}
+
+/// Replaces the messages that would otherwise be dropped to fit `limit` with a summary of
+/// them, placed after the system prompt. Returns how many messages were summarized.
+async fn compact_history(api: &Api, messages: &mut Vec<Message>, limit: usize) -> Result<usize, Box<dyn std::error::Error>> {
+    let count = conversation::trim_history(&mut messages.clone(), limit);
+    if count == 0 {
+        return Ok(0);
+    }
+
+    let first = usize::from(messages.first().is_some_and(|m| m.role == "system"));
+    let transcript = messages[first..first + count]
+        .iter()
+        .map(|m| format!("{}: {}", m.role, m.text()))
+        .collect::<Vec<_>>()
+        .join("\n\n");
+
+    let summary_request = ChatRequest {
+        messages: vec![
+            text_message("system", "You summarize conversations. Condense the conversation you are given into a short summary that keeps the facts, decisions, open questions, and user preferences a later reply would need. Output only the summary.".to_string()),
+            text_message("user", transcript),
+        ],
+        temperature: 0.3,
+        top_p: 0.95,
+        max_tokens: 1024,
+        stream: false,
+        stream_options: None,
+        n: None,
+        response_format: None,
+        style: api.style.clone(),
+    };
+    let summary = api.complete(&summary_request).await?;
+
+    messages.splice(first..first + count, [text_message("system", format!("Summary of the earlier conversation:\n{}", summary.trim()))]);
+    Ok(count)
+}
+
+/// Writes the content of each choice to `candidate_<n>.txt` in `dir` for --output-split,
+/// returning the paths written. Choices without content, such as refusals, are skipped.
+fn write_candidates(dir: &str, choices: &[Value], clean: impl Fn(&str) -> String) -> Result<Vec<String>, String> {
+    std::fs::create_dir_all(dir).map_err(|e| format!("could not create '{}': {}", dir, e))?;
+    let mut created = Vec::new();
+    for (index, choice) in choices.iter().enumerate() {
+        let Some(content) = choice["message"]["content"].as_str() else {
+            warn!(code = "output_split"; "Candidate {} has no content; not writing a file for it", index + 1);
+            continue;
+        };
+        let path = std::path::Path::new(dir).join(format!("candidate_{}.txt", index + 1));
+        std::fs::write(&path, format!("{}\n", clean(content).trim_end())).map_err(|e| format!("could not write {}: {}", path.display(), e))?;
+        created.push(path.display().to_string());
+    }
+    Ok(created)
+}
+
+/// Sends a one-token request for --warmup, so a deployment that has been idle is ready
+/// (and a connection is open) before the real requests. Failures are only warned about,
+/// since the real request will report them properly. Returns whether the deployment answered.
+async fn warmup(api: &Api) -> bool {
+    let request = ChatRequest {
+        messages: vec![text_message("user", "ping".to_string())],
+        temperature: 0.0,
+        top_p: 1.0,
+        max_tokens: 1,
+        stream: false,
+        stream_options: None,
+        n: None,
+        response_format: None,
+        style: api.style.clone(),
+    };
+    // Sent once, straight to the deployment the next request goes to, so it doesn't move
+    // the round-robin turn on, use up retries or rate limit turns, or write error reports
+    let deployment = match &api.round_robin {
+        Some(round_robin) => round_robin.peek(&api.deployments),
+        None => &api.deployments[0],
+    };
+    let (endpoint, api_key, _) = api.first_endpoint(deployment);
+    let url = chat_completions_url(endpoint, deployment, api.api_versions.get(deployment).map(String::as_str));
+    let start = std::time::Instant::now();
+    match send_chat_request(&api.client, &url, api_key, &request, api.retry_policy.timeout).await {
+        Ok(response) if response.status().is_success() => {
+            diagnostics::status("warmup", format!("Warmup: '{}' answered in {:.2} s", deployment, start.elapsed().as_secs_f64()));
+            true
+        }
+        Ok(response) => {
+            warn!(code = "warmup"; "The warmup request to '{}' returned HTTP {} after {:.2} s", deployment, response.status().as_u16(), start.elapsed().as_secs_f64());
+            false
+        }
+        Err(e) => {
+            warn!(code = "warmup"; "The warmup request failed: {}", e);
+            false
+        }
+    }
+}
+
+/// Prints the token counts from the final chunk of a --stream-usage stream.
+fn report_stream_usage(usage: &Value) {
+    match (usage["prompt_tokens"].as_u64(), usage["completion_tokens"].as_u64(), usage["total_tokens"].as_u64()) {
+        (Some(prompt), Some(completion), Some(total)) => diagnostics::status("stream_usage", format!("Usage: {} prompt + {} completion = {} tokens", prompt, completion, total)),
+        _ => warn!(code = "stream_usage"; "The stream didn't report usage; the deployment or API version may not support stream_options"),
+    }
+}
+
+/// Prints the prompt cache statistics from a response's usage. Azure reports tokens read
+/// from the cache; services that also charge for writing to it report those separately.
+fn report_cache_usage(usage: &Value) {
+    let prompt_tokens = usage["prompt_tokens"].as_u64();
+    let read = usage["prompt_tokens_details"]["cached_tokens"].as_u64().or(usage["cache_read_input_tokens"].as_u64());
+    let written = usage["cache_creation_input_tokens"].as_u64();
+    match (read, prompt_tokens) {
+        (Some(read), Some(prompt_tokens)) => diagnostics::status("prompt_cache", format!("Prompt cache: {} of {} prompt tokens read from the cache", read, prompt_tokens)),
+        (Some(read), None) => diagnostics::status("prompt_cache", format!("Prompt cache: {} prompt tokens read from the cache", read)),
+        (None, _) if written.is_none() => diagnostics::status("prompt_cache", "Prompt cache: the response didn't report cache usage"),
+        (None, _) => {}
+    }
+    if let Some(written) = written {
+        diagnostics::status("prompt_cache", format!("Prompt cache: {} prompt tokens written to the cache", written));
+    }
+}
+
+/// Asks for the rest of a response that stopped at max_tokens, up to MAX_CONTINUATIONS times,
+/// and folds the continuations into `response_json` as if they had been one response.
+async fn continue_truncated(api: &Api, chat_request: &ChatRequest, response_json: &mut Value) -> Result<(), Box<dyn std::error::Error>> {
+    let mut request = chat_request.clone();
+    request.stream = false;
+    let mut content = response_json["choices"][0]["message"]["content"].as_str().unwrap_or_default().to_string();
+
+    for continuation in 1..=MAX_CONTINUATIONS {
+        info!("Response stopped at max_tokens; asking for the rest (continuation {} of {})", continuation, MAX_CONTINUATIONS);
+        let mut messages = chat_request.messages.clone();
+        messages.push(text_message("assistant", content.clone()));
+        messages.push(text_message("user", "Continue exactly where you left off, without repeating anything.".to_string()));
+        request.messages = messages;
+
+        let served = api.send(&request).await?;
+        let status = served.response.status();
+        let next = read_json(served.response).await?;
+        let Some(more) = next["choices"][0]["message"]["content"].as_str().filter(|_| status.is_success()) else {
+            warn!(code = "continue_failed"; "Could not continue the truncated response: HTTP {}", status.as_u16());
+            break;
+        };
+
+        content.push_str(more);
+        for field in ["prompt_tokens", "completion_tokens", "total_tokens"] {
+            if let (Some(total), Some(added)) = (response_json["usage"][field].as_u64(), next["usage"][field].as_u64()) {
+                response_json["usage"][field] = json!(total + added);
+            }
+        }
+        response_json["choices"][0]["finish_reason"] = next["choices"][0]["finish_reason"].clone();
+        if next["choices"][0]["finish_reason"] != "length" {
+            break;
+        }
+    }
+
+    response_json["choices"][0]["message"]["content"] = json!(content);
+    Ok(())
+}
+
+fn save_error_report(dir: &str, chat_request: &ChatRequest, failure: &error_report::Failure) {
+    match error_report::save(dir, chat_request, failure) {
+        Ok(path) => diagnostics::status("error_report", format!("Saved the failed request to {}", path.display())),
+        Err(e) => warn!(code = "error_report"; "Could not save the failed request: {}", e),
+    }
+}
+
+/// Waits for `request`, giving up once `deadline` passes. Timeouts of a single attempt and
+/// of the overall --max-time budget both exit with EXIT_TIMEOUT.
+async fn finish_by<T>(deadline: Option<std::time::Instant>, request: impl std::future::Future<Output = Result<T, Box<dyn std::error::Error>>>) -> Result<T, Box<dyn std::error::Error>> {
+    let result = match deadline {
+        Some(deadline) => match tokio::time::timeout_at(tokio::time::Instant::from_std(deadline), request).await {
+            Ok(result) => result,
+            Err(_) => {
+                diagnostics::error("max_time", format!("gave up after --max-time was reached ({} attempt(s) made).", ATTEMPTS.load(Ordering::Relaxed)));
+                std::process::exit(EXIT_TIMEOUT);
+            }
+        },
+        None => request.await,
+    };
+
+    if let Err(e) = &result {
+        if e.downcast_ref::<reqwest::Error>().is_some_and(|e| e.is_timeout()) {
+            diagnostics::error("timeout", format!("the request timed out ({} attempt(s) made).", ATTEMPTS.load(Ordering::Relaxed)));
+            std::process::exit(EXIT_TIMEOUT);
+        }
+    }
+    result
+}
+
+/// Sends the request, retrying throttled (429) and server error (5xx) responses up to
+/// `max_retries` times and timed out requests up to `timeout_retries` times. The two
+/// budgets are independent, so one request may be retried up to their sum. Waits for the
+/// server's Retry-After when given, and otherwise backs off exponentially from one second.
+/// Returns the last response once retries run out. Every attempt waits its turn under the
+/// deployment's rate limit.
+async fn send_with_retries(client: &reqwest::Client, target: (&str, &str), api_key: &str, chat_request: &ChatRequest, retry_policy: &RetryPolicy) -> Result<reqwest::Response, reqwest::Error> {
+    let (url, deployment) = target;
+    let mut attempt = 0;
+    let mut timeout_attempt = 0;
+    loop {
+        retry_policy.rate_limiter.wait(deployment).await;
+        let response = match send_chat_request(client, url, api_key, chat_request, retry_policy.timeout).await {
+            Ok(response) => response,
+            Err(e) if e.is_timeout() && timeout_attempt < retry_policy.timeout_retries && retry_policy.take_retry() => {
+                timeout_attempt += 1;
+                warn!(code = "timeout_retry"; "Request timed out; retrying (timeout retry {} of {})", timeout_attempt, retry_policy.timeout_retries);
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+
+        let status = response.status();
+        if !(status == reqwest::StatusCode::TOO_MANY_REQUESTS || status.is_server_error()) || attempt >= retry_policy.max_retries || !retry_policy.take_retry() {
+            return Ok(response);
+        }
+
+        let delay = response
+            .headers()
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok())
+            .unwrap_or(1 << attempt.min(6));
+        attempt += 1;
+        warn!(code = "retry"; "HTTP {}; retrying in {} s (retry {} of {})", status.as_u16(), delay, attempt, retry_policy.max_retries);
+        tokio::time::sleep(std::time::Duration::from_secs(delay)).await;
+    }
 }
 
-#[derive(Debug, Serialize)]
-struct ChatRequest {
-    messages: Vec<Message>,
-    temperature: f32,
-    top_p: f32,
-    max_tokens: i32,
+/// Diagnostic messages go to stderr at `warn` and above unless `--log-level` or
+/// `RUST_LOG` ask for more. `--log-level` only applies to this tool's own messages,
+/// while `RUST_LOG` accepts the full env_logger filter syntax.
+fn init_logging(log_level: Option<&str>, verbose: bool, diagnostics_json: bool) {
+    let log_level = log_level.or(if verbose { Some("info") } else { None });
+    let mut builder = env_logger::Builder::new();
+    builder.filter_level(log::LevelFilter::Warn);
+    match (log_level, std::env::var("RUST_LOG")) {
+        (Some(level), _) => {
+            builder.filter_module(module_path!(), level.parse().unwrap_or(log::LevelFilter::Warn));
+        }
+        (None, Ok(filters)) => {
+            builder.parse_filters(&filters);
+        }
+        (None, Err(_)) => {}
+    }
+    if diagnostics_json {
+        builder.format(diagnostics::format_log);
+    }
+    builder.init();
 }
 
-#[derive(Parser)]
-#[command(name = "ai")]
-#[command(about = "A command line tool for interacting with Azure OpenAI services")]
-#[command(after_help = "\
-CREDENTIALS:
-    The tool securely stores the following credentials:
-    - Azure OpenAI API Key
-    - Azure OpenAI Endpoint
-    - Azure OpenAI Deployment Name
+/// Sends a one-token request to check that the endpoint, key, and deployment work together,
+/// diagnosing which of them is at fault when they don't.
+async fn run_connection_test(model: Option<String>, config: &config::Config) -> Result<(), Box<dyn std::error::Error>> {
+    let api_key = get_credential("api_key")?;
+    let endpoint = get_credential("endpoint")?;
+    let deployment = get_deployment(model, config)?;
+    require_credentials(&[("api_key", &api_key), ("endpoint", &endpoint), ("deployment", &deployment)]);
+    let (endpoint, api_key) = match mapped_endpoint(config, &deployment)? {
+        Some(mapped) => (mapped.endpoint, mapped.api_key),
+        None => (endpoint, api_key),
+    };
 
-    On first launch, you will be prompted to enter these credentials.
-    They will be stored securely in the system keyring for future use.
-    Use --delete-keys to remove stored credentials.")]
-struct Cli {
-    /// Input to process - either a file path or direct text
-    #[arg(index = 1)]
-    input: Option<String>,
+    let chat_request = ChatRequest {
+        messages: vec![text_message("user", "ping".to_string())],
+        temperature: 0.0,
+        top_p: 1.0,
+        max_tokens: 1,
+        stream: false,
+        stream_options: None,
+        n: None,
+        response_format: None,
+        style: RequestStyle::new(&config.parameters_for(&deployment)),
+    };
 
-    /// System prompt from file or direct text
-    #[arg(long, value_name = "PROMPT")]
-    prompt: Option<String>,
+    let client = reqwest::Client::new();
+    let url = chat_completions_url(&endpoint, &deployment, config.parameters_for(&deployment).api_version.as_deref());
+    let start = std::time::Instant::now();
 
-    /// Delete all stored credentials
-    #[arg(long)]
-    delete_keys: bool,
-}
+    let response = match send_chat_request(&client, &url, &api_key, &chat_request, None).await {
+        Ok(response) => response,
+        Err(e) => {
+            println!("FAILED: could not reach the endpoint {}", endpoint);
+            println!("    {}", e);
+            println!("Check that the endpoint URL is correct and reachable from this machine.");
+            std::process::exit(1);
+        }
+    };
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let cli = Cli::parse();
+    let status = response.status();
+    let response_json: Value = response.json().await.unwrap_or(Value::Null);
+    let error_code = response_json["error"]["code"].as_str().unwrap_or("");
 
-    if cli.delete_keys {
-        delete_credentials()?;
-        println!("All credentials deleted from secure storage.");
+    if status.is_success() {
+        println!("OK: deployment '{}' at {} responded in {} ms.", deployment, endpoint, start.elapsed().as_millis());
         return Ok(());
     }
 
-    let input = match cli.input {
-        Some(input_arg) => fs::read_to_string(&input_arg).unwrap_or_else(|_| input_arg),
-        None => {
-            let _ = Cli::parse_from(&["ai", "--help"]);
-            std::process::exit(1);
+    match status.as_u16() {
+        401 | 403 => {
+            println!("FAILED: the endpoint rejected the API key (HTTP {}).", status.as_u16());
+            println!("Use --delete-keys and run again (or --setup) to enter a new key.");
         }
-    };
+        404 if error_code == "DeploymentNotFound" => {
+            println!("FAILED: no deployment named '{}' exists at {}.", deployment, endpoint);
+            println!("Check the deployment name in the Azure portal, then use --setup to update it.");
+        }
+        404 => {
+            println!("FAILED: the endpoint {} doesn't look like an Azure OpenAI resource (HTTP 404).", endpoint);
+            println!("Check that the endpoint URL is correct.");
+        }
+        _ => {
+            println!("FAILED: the service returned HTTP {}.", status.as_u16());
+            if !response_json.is_null() {
+                println!("{}", serde_json::to_string_pretty(&response_json)?);
+            }
+        }
+    }
+    std::process::exit(1);
+}
 
-    let system_prompt = match cli.prompt {
-        Some(prompt_arg) => fs::read_to_string(&prompt_arg).unwrap_or_else(|_| prompt_arg),
-        None => String::from("You are an AI assistant that helps people find information.")
-    };
+/// How long --health waits for the deployment when --timeout isn't given
+const HEALTH_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
 
-    // Read from a secure credential store
+/// Sends a one-token request and prints a single `key=value` status line for monitoring,
+/// exiting non-zero unless the deployment answered successfully.
+async fn run_health_check(model: Option<String>, config: &config::Config, timeout: Option<std::time::Duration>) -> Result<(), Box<dyn std::error::Error>> {
     let api_key = get_credential("api_key")?;
     let endpoint = get_credential("endpoint")?;
-    let deployment = get_credential("deployment")?;
+    let deployment = get_deployment(model, config)?;
+    require_credentials(&[("api_key", &api_key), ("endpoint", &endpoint), ("deployment", &deployment)]);
+    let (endpoint, api_key) = match mapped_endpoint(config, &deployment)? {
+        Some(mapped) => (mapped.endpoint, mapped.api_key),
+        None => (endpoint, api_key),
+    };
 
-    // Create the chat request
     let chat_request = ChatRequest {
-        messages: vec![
-            Message {
-                role: "system".to_string(),
-                content: vec![Content {
-                    content_type: "text".to_string(),
-                    text: system_prompt,
-                }],
-            },
-            Message {
-                role: "user".to_string(),
-                content: vec![Content {
-                    content_type: "text".to_string(),
-                    text: input.clone(),  // Clone here so we can use input later
-                }],
-            },
-        ],
-        temperature: 0.7,
-        top_p: 0.95,
-        max_tokens: 16384,
+        messages: vec![text_message("user", "ping".to_string())],
+        temperature: 0.0,
+        top_p: 1.0,
+        max_tokens: 1,
+        stream: false,
+        stream_options: None,
+        n: None,
+        response_format: None,
+        style: RequestStyle::new(&config.parameters_for(&deployment)),
     };
 
-    // Create HTTP client
     let client = reqwest::Client::new();
+    let url = chat_completions_url(&endpoint, &deployment, config.parameters_for(&deployment).api_version.as_deref());
+    let start = std::time::Instant::now();
+    let result = send_chat_request(&client, &url, &api_key, &chat_request, Some(timeout.unwrap_or(HEALTH_TIMEOUT))).await;
+    let latency_ms = start.elapsed().as_millis();
 
-    // Prepare API request
-    let url = format!("{}/openai/deployments/{}/chat/completions?api-version=2024-02-15-preview", endpoint, deployment);
-    let response = client
-        .post(&url)
-        .header("api-key", api_key)
-        .json(&chat_request)
-        .send()
-        .await?;
+    let (healthy, status, error) = match result {
+        Ok(response) if response.status().is_success() => (true, response.status().as_u16().to_string(), None),
+        Ok(response) => {
+            let status = response.status().as_u16();
+            let response_json: Value = response.json().await.unwrap_or(Value::Null);
+            let message = response_json["error"]["message"].as_str().unwrap_or("request failed").to_string();
+            (false, status.to_string(), Some(message))
+        }
+        Err(e) if e.is_timeout() => (false, "none".to_string(), Some("timed out".to_string())),
+        Err(e) => (false, "none".to_string(), Some(format!("unreachable: {}", e))),
+    };
 
-    let response_json: Value = response.json().await?;
-    if let Some(choices) = response_json["choices"].as_array() {
-        if let Some(message) = choices[0]["message"]["content"].as_str() {
-            println!("{}", message);
-        } else {
-            print_error_response(&response_json, &input)?;
+    let mut line = format!(
+        "{} endpoint={} deployment={} status={} latency_ms={}",
+        if healthy { "healthy" } else { "unhealthy" },
+        endpoint,
+        deployment,
+        status,
+        latency_ms
+    );
+    if let Some(error) = error {
+        line.push_str(&format!(" error={}", json!(error)));
+    }
+    println!("{}", line);
+
+    if !healthy {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// Finds a relative file that isn't in the current directory in the first --include-dir
+/// that has it. Absolute paths, and files the current directory has, are left alone.
+fn find_include(path: &str, include_dirs: &[String]) -> Option<std::path::PathBuf> {
+    let relative = std::path::Path::new(path);
+    if relative.is_absolute() || relative.exists() {
+        return None;
+    }
+    include_dirs.iter().map(|dir| std::path::Path::new(dir).join(relative)).find(|candidate| candidate.is_file())
+}
+
+/// Reads `arg` as a file if one exists at that path, otherwise treats it as literal text.
+/// Only a missing file (or text that can't be a path at all) falls back to the literal;
+/// other failures such as permission errors or invalid UTF-8 are reported and exit.
+fn read_file_or_text(arg: String, utf8_lossy: bool) -> String {
+    match read_file(&arg, utf8_lossy) {
+        Ok(contents) => contents,
+        Err(e) if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::InvalidFilename | ErrorKind::NotADirectory) => arg,
+        Err(e) => {
+            diagnostics::error("read_failed", format!("could not read file '{}': {}", arg, e));
+            std::process::exit(1);
         }
+    }
+}
+
+/// Reads a file as it was at a git revision, from a --prompt-git REF:PATH.
+fn git_show(spec: &str, utf8_lossy: bool) -> Result<String, String> {
+    // A leading dash would make git read the value as an option, such as --output
+    if !spec.contains(':') || spec.starts_with('-') {
+        return Err(format!("'{}' should be a git revision and path, such as HEAD~1:prompts/review.txt", spec));
+    }
+    let output = std::process::Command::new("git").args(["show", spec]).output().map_err(|e| match e.kind() {
+        ErrorKind::NotFound => "git was not found; install it or add it to the PATH to use --prompt-git".to_string(),
+        _ => format!("could not run git: {}", e),
+    })?;
+    if !output.status.success() {
+        return Err(format!("git could not read '{}': {}", spec, String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    let limit = MAX_INPUT_BYTES.load(Ordering::Relaxed);
+    let size = output.stdout.len() as u64;
+    if limit > 0 && size > limit {
+        return Err(format!("'{}' is {} bytes, over the --max-input-bytes limit of {}", spec, size, limit));
+    }
+    match String::from_utf8(output.stdout) {
+        Ok(text) => Ok(text),
+        Err(e) if utf8_lossy => Ok(String::from_utf8_lossy(e.as_bytes()).into_owned()),
+        Err(_) => Err(format!("'{}' is not valid UTF-8; pass --utf8-lossy to read it anyway", spec)),
+    }
+}
+
+/// With `utf8_lossy`, invalid UTF-8 sequences are replaced rather than treated as an error.
+/// Files over the --max-input-bytes limit are an error rather than being read into memory.
+fn read_file(path: &str, utf8_lossy: bool) -> std::io::Result<String> {
+    if utf8_lossy {
+        read_input(path).map(|bytes| String::from_utf8_lossy(&bytes).into_owned())
     } else {
-        print_error_response(&response_json, &input)?;
+        read_input_to_string(path)
     }
+}
+
+fn input_too_large(size: &str, limit: u64) -> std::io::Error {
+    std::io::Error::other(format!(
+        "it is {} bytes, over the --max-input-bytes limit of {}. Send an excerpt (for example with head -c), or raise the limit if the whole file is intended.",
+        size, limit
+    ))
+}
 
+/// Fails for a file over the --max-input-bytes limit, before it is read into memory.
+fn check_input_size(path: &str) -> std::io::Result<()> {
+    let limit = MAX_INPUT_BYTES.load(Ordering::Relaxed);
+    let size = fs::metadata(path)?.len();
+    if limit > 0 && size > limit {
+        return Err(input_too_large(&size.to_string(), limit));
+    }
     Ok(())
 }
 
+/// Reads a whole file, within the --max-input-bytes limit.
+fn read_input(path: &str) -> std::io::Result<Vec<u8>> {
+    check_input_size(path)?;
+    fs::read(path)
+}
+
+/// Like `read_input`, for a file that has to be UTF-8.
+fn read_input_to_string(path: &str) -> std::io::Result<String> {
+    check_input_size(path)?;
+    fs::read_to_string(path)
+}
+
+/// Reads the rest of `reader`, such as stdin, stopping with an error once it passes the
+/// --max-input-bytes limit.
+fn read_input_stream(reader: impl Read) -> std::io::Result<Vec<u8>> {
+    let limit = MAX_INPUT_BYTES.load(Ordering::Relaxed);
+    let mut bytes = Vec::new();
+    reader.take(if limit > 0 { limit.saturating_add(1) } else { u64::MAX }).read_to_end(&mut bytes)?;
+    if limit > 0 && bytes.len() as u64 > limit {
+        return Err(input_too_large(&format!("more than {}", limit), limit));
+    }
+    Ok(bytes)
+}
+
+/// Sandwiches the input between the --prepend and --append text, separated by blank lines.
+fn wrap_input(input: String, prepend: Option<String>, append: Option<String>) -> String {
+    let mut wrapped = match prepend {
+        Some(prepend) => format!("{}\n\n{}", prepend.trim_end(), input),
+        None => input,
+    };
+    if let Some(append) = append {
+        wrapped = format!("{}\n\n{}", wrapped.trim_end(), append);
+    }
+    wrapped
+}
+
+/// Reads the --messages file: a JSON array of `{"role": ..., "content": ...}` objects whose
+/// role is user or assistant.
+fn load_example_messages(path: &str) -> Result<Vec<Message>, String> {
+    let contents = read_file(path, false).map_err(|e| format!("could not read messages file '{}': {}", path, e))?;
+    let messages: Vec<Message> = serde_json::from_str(&contents).map_err(|e| format!("messages file '{}' is not valid: {}", path, e))?;
+    if let Some((index, message)) = messages.iter().enumerate().find(|(_, m)| m.role != "user" && m.role != "assistant") {
+        return Err(format!("message {} in '{}' has the role '{}'; only user and assistant messages can be given", index + 1, path, message.role));
+    }
+    Ok(messages)
+}
+
+/// A unified diff of two files, or an empty string if they are the same.
+fn diff_files(old_path: &str, new_path: &str, utf8_lossy: bool) -> String {
+    let read = |path: &str| {
+        read_file(path, utf8_lossy).unwrap_or_else(|e| {
+            diagnostics::error("read_failed", format!("could not read file '{}': {}", path, e));
+            std::process::exit(1);
+        })
+    };
+    let (old, new) = (read(old_path), read(new_path));
+    if old == new {
+        return String::new();
+    }
+    similar::TextDiff::from_lines(&old, &new).unified_diff().header(old_path, new_path).to_string()
+}
+
+/// Joins several files into one input, each preceded by a header naming the file.
+fn concat_files(paths: &[String], utf8_lossy: bool) -> String {
+    let mut combined = String::new();
+    for path in paths {
+        let contents = read_file(path, utf8_lossy).unwrap_or_else(|e| {
+            diagnostics::error("read_failed", format!("could not read file '{}': {}", path, e));
+            std::process::exit(1);
+        });
+        if !combined.is_empty() {
+            combined.push('\n');
+        }
+        combined.push_str(&format!("===== {} =====\n", path));
+        combined.push_str(&contents);
+        if !contents.ends_with('\n') {
+            combined.push('\n');
+        }
+    }
+    combined
+}
+
 fn get_credential(cred_type: &str) -> Result<String, Box<dyn std::error::Error>> {
-    let (keyring_id, prompt_message) = match cred_type {
-        "api_key" => (
-            "azure_openai",
-            "Please enter your API key (input will be hidden): "
-        ),
-        "endpoint" => (
-            "azure_openai_endpoint",
-            "Please enter your endpoint (e.g., https://your-resource.openai.azure.com): "
-        ),
-        "deployment" => (
-            "azure_openai_deployment",
-            "Please enter your deployment name: "
-        ),
-        _ => return Err("Invalid credential type".into()),
-    };
-
-    let keyring_entry = keyring::Entry::new("actionitems", keyring_id)?;
-    
-    // Try to get from keyring first
-    match keyring_entry.get_password() {
-        Ok(value) => Ok(value.trim().to_string()),
-        Err(_) => {
+    let info = credential_info(cred_type)?;
+
+    // A --credentials-file comes first, then environment variables (including any loaded
+    // from a .env file)
+    if let Some(value) = credential_store::from_credentials_file(cred_type) {
+        debug!("Using {} from the credentials file", cred_type);
+        return Ok(value);
+    }
+    if let Some(value) = env_credential(cred_type, &info) {
+        return Ok(value);
+    }
+
+    get_keyring_credential(cred_type, info.keyring_id, info.prompt)
+}
+
+/// Finds a credential the way `get_credential` does, along with where it came from, but
+/// without asking for it when it is missing.
+fn credential_with_source(cred_type: &str) -> Result<Option<(String, String)>, Box<dyn std::error::Error>> {
+    let info = credential_info(cred_type)?;
+    if let Some(value) = credential_store::from_credentials_file(cred_type) {
+        return Ok(Some((value, "--credentials-file".to_string())));
+    }
+    if let Some(value) = env_credential(cred_type, &info) {
+        return Ok(Some((value, format!("environment ({})", env_name(info.env_var)))));
+    }
+    let store = match credential_store::backend() {
+        credential_store::Backend::Keyring => "keyring",
+        credential_store::Backend::File => "credential file",
+    };
+    Ok(credential_store::get(info.keyring_id)?.map(|value| (value.trim().to_string(), store.to_string())))
+}
+
+/// Hides a secret, keeping the last four characters of long ones so keys can be told apart.
+fn mask(secret: &str) -> String {
+    let chars: Vec<char> = secret.chars().collect();
+    if chars.len() < 16 {
+        return "********".to_string();
+    }
+    let shown: String = chars[chars.len() - 4..].iter().collect();
+    format!("********{}", shown)
+}
+
+/// Prints the effective settings for --dump-config, in the same order of precedence the
+/// request would use them, without sending any request.
+fn dump_config(cli: &Cli, model: Option<String>, config: &config::Config) -> Result<(), Box<dyn std::error::Error>> {
+    let not_set = || ("(not set)".to_string(), String::new());
+    let mut rows: Vec<(&str, String, String)> = Vec::new();
+
+    let config_file = config::config_path().map_or("(none)".to_string(), |path| path.display().to_string());
+    let config_source = if config::config_path().is_some_and(|path| path.exists()) { "found" } else { "not found" };
+    rows.push(("config file", config_file, config_source.to_string()));
+    let store_source = if cli.store == credential_store::Backend::Keyring { "default" } else { "--store" };
+    rows.push(("credential store", format!("{:?}", cli.store).to_lowercase(), store_source.to_string()));
+
+    let (endpoint, source) = credential_with_source("endpoint")?.unwrap_or_else(not_set);
+    rows.push(("endpoint", endpoint, source));
+    let (api_key, source) = credential_with_source("api_key")?.map(|(key, source)| (mask(&key), source)).unwrap_or_else(not_set);
+    rows.push(("api_key", api_key, source));
+
+    let deployment = match model {
+        Some(name) => Some((name, "--model".to_string())),
+        None => match credential_store::from_credentials_file("deployment") {
+            Some(value) => Some((value, "--credentials-file".to_string())),
+            None => match (env_credential("deployment", &credential_info("deployment")?), &config.default_deployment) {
+                (Some(value), _) => Some((value, format!("environment ({})", env_name("AZURE_OPENAI_DEPLOYMENT")))),
+                (None, Some(name)) => Some((config.resolve_deployment(name), "config (default_deployment)".to_string())),
+                (None, None) => credential_with_source("deployment")?,
+            },
+        },
+    };
+    let parameters = deployment.as_ref().map(|(name, _)| config.parameters_for(name)).unwrap_or_default();
+    if let Some(endpoint) = deployment.as_ref().and_then(|(name, _)| config.endpoint_for(name)) {
+        let api_key = credential_store::get(&endpoint_key_id(&endpoint))?.map_or("(not set)".to_string(), |key| mask(key.trim()));
+        rows[2] = ("endpoint", endpoint, "config ([endpoints])".to_string());
+        rows[3] = ("api_key", api_key, "stored for that endpoint".to_string());
+    }
+    let (name, source) = deployment.unwrap_or_else(not_set);
+    rows.push(("deployment", name, source));
+
+    fn parameter<T: std::fmt::Display + std::str::FromStr>(flag: (&str, Option<T>), from_config: Option<T>, env_var: &str, default: T) -> (String, String)
+    where
+        T::Err: std::fmt::Display,
+    {
+        let (flag_name, flag) = flag;
+        match (flag, from_config) {
+            (Some(value), _) => (value.to_string(), flag_name.to_string()),
+            (None, Some(value)) => (value.to_string(), "config ([parameters])".to_string()),
+            (None, None) if std::env::var(env_name(env_var)).is_ok_and(|value| !value.trim().is_empty()) => {
+                (env_parameter(env_var, default).to_string(), format!("environment ({})", env_name(env_var)))
+            }
+            (None, None) => (default.to_string(), "default".to_string()),
+        }
+    }
+    let sampling_note = |(value, source): (String, String)| {
+        if parameters.omit_sampling {
+            (format!("{} (not sent)", value), format!("{}; omit_sampling in config", source))
+        } else {
+            (value, source)
+        }
+    };
+    let (value, source) = sampling_note(parameter(("--temperature", cli.temperature), parameters.temperature, "AI_TEMPERATURE", 0.7));
+    rows.push(("temperature", value, source));
+    let (value, source) = sampling_note(parameter(("--top-p", cli.top_p), parameters.top_p, "AI_TOP_P", 0.95));
+    rows.push(("top_p", value, source));
+    let (value, source) = parameter(("--max-tokens", cli.max_tokens), parameters.max_tokens, "AI_MAX_TOKENS", 16384);
+    rows.push((if parameters.max_completion_tokens { "max_completion_tokens" } else { "max_tokens" }, value, source));
+    if parameters.developer_role {
+        rows.push(("system prompt role", "developer".to_string(), "config ([parameters])".to_string()));
+    }
+    match &parameters.api_version {
+        Some(version) => rows.push(("api_version", version.clone(), "config ([parameters])".to_string())),
+        None if cli.stream_usage => rows.push(("api_version", STREAM_USAGE_API_VERSION.to_string(), "--stream-usage".to_string())),
+        None => rows.push(("api_version", DEFAULT_API_VERSION.to_string(), "default".to_string())),
+    }
+
+    let width = rows.iter().map(|(name, _, _)| name.len()).max().unwrap_or(0);
+    let value_width = rows.iter().map(|(_, value, _)| value.chars().count()).max().unwrap_or(0);
+    for (name, value, source) in rows {
+        println!("{:width$}  {:value_width$}  {}", name, value, source, width = width, value_width = value_width);
+    }
+    Ok(())
+}
+
+/// Picks the deployment from --model, then the --credentials-file, then the environment,
+/// then `default_deployment` in the config file, and only then the credential store.
+fn get_deployment(model: Option<String>, config: &config::Config) -> Result<String, Box<dyn std::error::Error>> {
+    if let Some(model) = model {
+        return Ok(model);
+    }
+
+    if let Some(value) = credential_store::from_credentials_file("deployment") {
+        debug!("Using deployment from the credentials file");
+        return Ok(value);
+    }
+    let info = credential_info("deployment")?;
+    if let Some(value) = env_credential("deployment", &info) {
+        return Ok(value);
+    }
+    if let Some(name) = &config.default_deployment {
+        debug!("Using deployment '{}' from the config file", name);
+        return Ok(config.resolve_deployment(name));
+    }
+
+    get_keyring_credential("deployment", info.keyring_id, info.prompt)
+}
+
+fn env_credential(cred_type: &str, info: &CredentialInfo) -> Option<String> {
+    let name = env_name(info.env_var);
+    let value = std::env::var(&name).ok()?;
+    if value.trim().is_empty() {
+        return None;
+    }
+    debug!("Using {} from environment variable {}", cred_type, name);
+    Some(value.trim().to_string())
+}
+
+/// Exits with a setup hint if any credential is blank, since a blank endpoint or deployment
+/// produces a malformed URL and a confusing error from the service.
+fn require_credentials(credentials: &[(&str, &str)]) {
+    for (cred_type, value) in credentials {
+        if value.trim().is_empty() {
+            diagnostics::error("empty_credential", format!("the {} credential is empty.", cred_type));
+            diagnostics::status("hint", format!("Run 'ai --set-credential {}' to set it, or 'ai --setup' to set up all credentials.", cred_type));
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Prompts for one credential and stores it, replacing any stored value.
+fn set_credential(cred_type: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let info = credential_info(cred_type)?;
+    let value = prompt_for_credential(cred_type, info.prompt)?;
+    if value.is_empty() {
+        diagnostics::error("empty_credential", format!("{} cannot be empty.", cred_type));
+        std::process::exit(1);
+    }
+    store_credential(cred_type, &value)?;
+    println!("{} securely stored for future use.", cred_type);
+    Ok(())
+}
+
+fn get_keyring_credential(cred_type: &str, keyring_id: &str, prompt_message: &str) -> Result<String, Box<dyn std::error::Error>> {
+    // Try to get from secure storage first
+    match credential_store::get(keyring_id)? {
+        Some(value) => {
+            debug!("Read {} from credential entry '{}'", cred_type, keyring_id);
+            Ok(value.trim().to_string())
+        }
+        None => {
+            info!("No {} in credential entry '{}'", cred_type, keyring_id);
             // Prompt for value if not found
             println!("{} not found in secure storage.", cred_type);
-            print!("{}", prompt_message);
-            std::io::stdout().flush()?;
-            
-            let value = if cred_type == "api_key" {
-                read_password()?.trim().to_string()
-            } else {
-                let mut input = String::new();
-                std::io::stdin().read_line(&mut input)?;
-                input.trim().to_string()
-            };
+            let value = prompt_for_credential(cred_type, prompt_message)?;
             
-            // Store in keyring for future use
-            keyring_entry.set_password(&value)?;
+            // Store for future use
+            credential_store::set(keyring_id, &value)?;
             println!("{} securely stored for future use.", cred_type);
             
             Ok(value)
@@ -176,46 +3056,279 @@ fn get_credential(cred_type: &str) -> Result<String, Box<dyn std::error::Error>>
     }
 }
 
-fn delete_credentials() -> Result<(), Box<dyn std::error::Error>> {
-    let cred_types = [
-        ("API key", "azure_openai"),
-        ("Endpoint", "azure_openai_endpoint"),
-        ("Deployment", "azure_openai_deployment"),
+/// A deployment's endpoint from its `[endpoints]` entry in ai.toml, with the API key for it.
+struct MappedEndpoint {
+    endpoint: String,
+    api_key: String,
+    /// The credential entry the key is stored under
+    keyring_id: String,
+}
+
+/// Looks up the deployment's `[endpoints]` entry, asking for its API key the first time.
+/// `None` when the deployment uses the stored endpoint.
+fn mapped_endpoint(config: &config::Config, deployment: &str) -> Result<Option<MappedEndpoint>, Box<dyn std::error::Error>> {
+    let Some(endpoint) = config.endpoint_for(deployment) else {
+        return Ok(None);
+    };
+    info!("Deployment '{}' is mapped to {}", deployment, endpoint);
+    let keyring_id = endpoint_key_id(&endpoint);
+    let prompt = format!("Please enter the API key for {} (input will be hidden): ", endpoint);
+    let api_key = get_keyring_credential("api_key", &keyring_id, &prompt)?;
+    Ok(Some(MappedEndpoint { endpoint, api_key, keyring_id }))
+}
+
+/// Fallback endpoints, and those of `[endpoints]` entries, each have their own API key,
+/// stored under a keyring id that includes the endpoint URL.
+fn endpoint_key_id(endpoint: &str) -> String {
+    format!("azure_openai@{}", endpoint)
+}
+
+struct CredentialInfo {
+    keyring_id: &'static str,
+    env_var: &'static str,
+    prompt: &'static str,
+}
+
+fn credential_info(cred_type: &str) -> Result<CredentialInfo, Box<dyn std::error::Error>> {
+    match cred_type {
+        "api_key" => Ok(CredentialInfo {
+            keyring_id: "azure_openai",
+            env_var: "AZURE_OPENAI_API_KEY",
+            prompt: "Please enter your API key (input will be hidden): ",
+        }),
+        "endpoint" => Ok(CredentialInfo {
+            keyring_id: "azure_openai_endpoint",
+            env_var: "AZURE_OPENAI_ENDPOINT",
+            prompt: "Please enter your endpoint (e.g., https://your-resource.openai.azure.com): ",
+        }),
+        "deployment" => Ok(CredentialInfo {
+            keyring_id: "azure_openai_deployment",
+            env_var: "AZURE_OPENAI_DEPLOYMENT",
+            prompt: "Please enter your deployment name: ",
+        }),
+        _ => Err("Invalid credential type".into()),
+    }
+}
+
+fn prompt_for_credential(cred_type: &str, prompt_message: &str) -> Result<String, Box<dyn std::error::Error>> {
+    print!("{}", prompt_message);
+    std::io::stdout().flush()?;
+
+    let value = if cred_type == "api_key" {
+        read_password()?.trim().to_string()
+    } else {
+        let mut input = String::new();
+        std::io::stdin().read_line(&mut input)?;
+        input.trim().to_string()
+    };
+    Ok(value)
+}
+
+fn store_credential(cred_type: &str, value: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let info = credential_info(cred_type)?;
+    credential_store::set(info.keyring_id, value)?;
+    debug!("Stored {} in credential entry '{}'", cred_type, info.keyring_id);
+    Ok(())
+}
+
+/// Walks through all three credentials, validating each one against the service
+/// before anything is stored.
+async fn run_setup() -> Result<(), Box<dyn std::error::Error>> {
+    println!("Azure OpenAI credential setup\n");
+    let client = reqwest::Client::new();
+
+    let endpoint = loop {
+        let value = prompt_for_credential("endpoint", credential_info("endpoint")?.prompt)?;
+        let value = value.trim_end_matches('/').to_string();
+        match reqwest::Url::parse(&value) {
+            Ok(url) if matches!(url.scheme(), "https" | "http") && url.host_str().is_some() => break value,
+            _ => println!("'{}' is not a valid endpoint URL. It should look like https://your-resource.openai.azure.com", value),
+        }
+    };
+
+    let (api_key, deployments) = loop {
+        let value = prompt_for_credential("api_key", credential_info("api_key")?.prompt)?;
+        match list_deployments(&client, &endpoint, &value).await {
+            Ok(deployments) => break (value, Some(deployments)),
+            Err(DeploymentListError::Unauthorized) => println!("The endpoint rejected that API key. Please try again."),
+            Err(DeploymentListError::Unsupported) => {
+                println!("Warning: this endpoint doesn't support listing deployments, so the key could not be verified.");
+                break (value, None);
+            }
+            Err(DeploymentListError::Other(e)) => {
+                diagnostics::error("connection_failed", format!("could not connect to {}: {}", endpoint, e));
+                std::process::exit(1);
+            }
+        }
+    };
+
+    if let Some(deployments) = &deployments {
+        if deployments.is_empty() {
+            println!("Warning: no deployments were found on this resource.");
+        } else {
+            println!("Available deployments:");
+            for deployment in deployments {
+                println!("    {:<30}{}", deployment.id, deployment.model);
+            }
+        }
+    }
+
+    let deployment = loop {
+        let value = prompt_for_credential("deployment", credential_info("deployment")?.prompt)?;
+        match &deployments {
+            Some(deployments) if !deployments.iter().any(|d| d.id == value) => {
+                println!("No deployment named '{}' exists on this resource.", value);
+            }
+            _ => break value,
+        }
+    };
+
+    store_credential("endpoint", &endpoint)?;
+    store_credential("api_key", &api_key)?;
+    store_credential("deployment", &deployment)?;
+
+    match deployments.iter().flatten().find(|d| d.id == deployment) {
+        Some(d) => println!("\nConnected to deployment '{}' (model {}).", d.id, d.model),
+        None => println!("\nCredentials stored for deployment '{}'.", deployment),
+    }
+    println!("Credentials securely stored for future use.");
+    Ok(())
+}
+
+struct Deployment {
+    id: String,
+    model: String,
+}
+
+enum DeploymentListError {
+    Unauthorized,
+    Unsupported,
+    Other(Box<dyn std::error::Error>),
+}
+
+async fn list_deployments(client: &reqwest::Client, endpoint: &str, api_key: &str) -> Result<Vec<Deployment>, DeploymentListError> {
+    let url = format!("{}/openai/deployments?api-version=2022-12-01", endpoint);
+    let response = client
+        .get(&url)
+        .header("api-key", api_key)
+        .send()
+        .await
+        .map_err(|e| DeploymentListError::Other(e.into()))?;
+
+    match response.status().as_u16() {
+        401 | 403 => return Err(DeploymentListError::Unauthorized),
+        404 => return Err(DeploymentListError::Unsupported),
+        _ => {}
+    }
+
+    let response_json: Value = response.json().await.map_err(|e| DeploymentListError::Other(e.into()))?;
+    let Some(data) = response_json["data"].as_array() else {
+        return Err(DeploymentListError::Other(format!("unexpected response: {}", response_json).into()));
+    };
+
+    Ok(data
+        .iter()
+        .filter_map(|d| {
+            Some(Deployment {
+                id: d["id"].as_str()?.to_string(),
+                model: d["model"].as_str().unwrap_or("unknown").to_string(),
+            })
+        })
+        .collect())
+}
+
+/// Models that can't serve chat completions, by a fragment of their model name
+const NON_CHAT_MODELS: &[&str] = &["embedding", "whisper", "tts", "dall-e", "instruct", "davinci", "babbage"];
+
+/// Picks the only chat deployment on the resource and stores it as the deployment credential.
+async fn autodetect_deployment(endpoint: &str, api_key: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let deployments = match list_deployments(&reqwest::Client::new(), endpoint, api_key).await {
+        Ok(deployments) => deployments,
+        Err(DeploymentListError::Unauthorized) => {
+            diagnostics::error("unauthorized", "the endpoint rejected the API key while listing deployments.");
+            std::process::exit(1);
+        }
+        Err(DeploymentListError::Unsupported) => {
+            diagnostics::error("unsupported", "this endpoint doesn't support listing deployments. Use --model or 'ai --set-credential deployment' instead.");
+            std::process::exit(1);
+        }
+        Err(DeploymentListError::Other(e)) => {
+            diagnostics::error("connection_failed", format!("could not list deployments on {}: {}", endpoint, e));
+            std::process::exit(1);
+        }
+    };
+
+    let chat: Vec<&Deployment> = deployments
+        .iter()
+        .filter(|d| !NON_CHAT_MODELS.iter().any(|fragment| d.model.contains(fragment)))
+        .collect();
+    match chat.as_slice() {
+        [] => {
+            diagnostics::error("no_deployments", "no chat deployments were found on this resource.");
+            std::process::exit(1);
+        }
+        [only] => {
+            info!("Detected deployment '{}' (model {})", only.id, only.model);
+            store_credential("deployment", &only.id)?;
+            Ok(only.id.clone())
+        }
+        several => {
+            let list: Vec<String> = several.iter().map(|d| format!("    {:<30}{}", d.id, d.model)).collect();
+            diagnostics::error("ambiguous_deployment", format!("this resource has several chat deployments. Choose one with --model:\n{}", list.join("\n")));
+            std::process::exit(1);
+        }
+    }
+}
+
+fn delete_credentials(fallback_endpoints: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut cred_types = vec![
+        ("API key".to_string(), "azure_openai".to_string()),
+        ("Endpoint".to_string(), "azure_openai_endpoint".to_string()),
+        ("Deployment".to_string(), "azure_openai_deployment".to_string()),
     ];
+    for endpoint in fallback_endpoints {
+        let endpoint = endpoint.trim_end_matches('/');
+        cred_types.push((format!("API key for {}", endpoint), endpoint_key_id(endpoint)));
+    }
 
     for (cred_name, keyring_id) in cred_types {
-        let keyring_entry = keyring::Entry::new("actionitems", keyring_id)?;
-        match keyring_entry.delete_password() {
-            Ok(_) => println!("{} deleted from secure storage.", cred_name),
-            Err(e) => {
-                if e.to_string().contains("No such key") {
-                    println!("No {} was stored.", cred_name);
-                } else {
-                    return Err(e.into());
-                }
-            }
+        debug!("Deleting credential entry '{}'", keyring_id);
+        if credential_store::delete(&keyring_id)? {
+            println!("{} deleted from secure storage.", cred_name);
+        } else {
+            println!("No {} was stored.", cred_name);
         }
     }
     Ok(())
 }
 
-fn print_error_response(response_json: &Value, input: &str) -> Result<(), Box<dyn std::error::Error>> {
+/// The ID Azure assigns each request, which its support staff ask for.
+fn request_id(response: &reqwest::Response) -> Option<String> {
+    ["apim-request-id", "x-ms-request-id", "x-request-id"]
+        .iter()
+        .find_map(|name| response.headers().get(*name))
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+}
+
+fn print_error_response(response_json: &Value, input: &str, keyring_id: &str, request_id: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
     // Check for 401 error
     if let Some(error) = response_json.get("error") {
         if let Some("401") = error.get("code").and_then(|c| c.as_str()) {
             // Delete the API key
-            let keyring_entry = keyring::Entry::new("actionitems", "azure_openai")?;
-            keyring_entry.delete_password()?;
-            println!("Authentication failed. API key has been cleared.");
-            println!("Please run the tool again to enter a new API key.");
+            info!("Deleting credential entry '{}' after authentication failure", keyring_id);
+            credential_store::delete(keyring_id)?;
+            diagnostics::error("authentication_failed", "authentication failed. The API key has been cleared.");
+            diagnostics::status("hint", "Please run the tool again to enter a new API key.");
             std::process::exit(1);
         }
     }
 
     // Print out how many tokens we sent
-    // Rough estimate: 1 token ≈ 4 chars in English
-    println!("Sent approximately {} tokens", input.len() / 4);
-    println!("\nRaw API Response:\n");
-    println!("{}", serde_json::to_string_pretty(response_json)?);
+    diagnostics::status("sent_tokens", format!("Sent approximately {} tokens", estimate_tokens(input)));
+    if let Some(id) = request_id {
+        diagnostics::status("request_id", format!("Request ID: {}", id));
+    }
+    diagnostics::status("response", format!("\nRaw API Response:\n\n{}", serde_json::to_string_pretty(response_json)?));
     Ok(())
 }
\ No newline at end of file
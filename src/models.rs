@@ -0,0 +1,108 @@
+use serde::Deserialize;
+use crate::config::Config;
+
+/// Token limits of a model.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ModelLimits {
+    /// Tokens the prompt and response can use together
+    pub context_window: usize,
+    /// Tokens the response alone can use
+    pub max_output_tokens: usize,
+}
+
+/// Limits of common models, by model name prefix. Azure deployments are often named after
+/// their model, so deployment names are matched against these.
+const KNOWN_MODELS: &[(&str, ModelLimits)] = &[
+    ("gpt-4o-mini", ModelLimits { context_window: 128_000, max_output_tokens: 16_384 }),
+    ("gpt-4o", ModelLimits { context_window: 128_000, max_output_tokens: 16_384 }),
+    ("gpt-4.1", ModelLimits { context_window: 1_047_576, max_output_tokens: 32_768 }),
+    ("gpt-4-turbo", ModelLimits { context_window: 128_000, max_output_tokens: 4_096 }),
+    ("gpt-4-1106", ModelLimits { context_window: 128_000, max_output_tokens: 4_096 }),
+    ("gpt-4-0125", ModelLimits { context_window: 128_000, max_output_tokens: 4_096 }),
+    ("gpt-4-32k", ModelLimits { context_window: 32_768, max_output_tokens: 4_096 }),
+    ("gpt-4", ModelLimits { context_window: 8_192, max_output_tokens: 4_096 }),
+    ("gpt-35-turbo-16k", ModelLimits { context_window: 16_384, max_output_tokens: 4_096 }),
+    ("gpt-35-turbo", ModelLimits { context_window: 16_385, max_output_tokens: 4_096 }),
+    ("o1-mini", ModelLimits { context_window: 128_000, max_output_tokens: 65_536 }),
+    ("o1", ModelLimits { context_window: 200_000, max_output_tokens: 100_000 }),
+    ("o3-mini", ModelLimits { context_window: 200_000, max_output_tokens: 100_000 }),
+    ("o3", ModelLimits { context_window: 200_000, max_output_tokens: 100_000 }),
+    ("o4-mini", ModelLimits { context_window: 200_000, max_output_tokens: 100_000 }),
+];
+
+/// Where a deployment's limits came from.
+pub enum Source {
+    Config,
+    BuiltIn(&'static str),
+}
+
+/// Finds the limits for a deployment, preferring the [models] table in ai.toml over the
+/// built-in table. Built-in entries match by the longest model name the deployment starts
+/// with, as `starts_with_model` compares them.
+pub fn lookup(deployment: &str, config: &Config) -> Option<(ModelLimits, Source)> {
+    if let Some(limits) = config.models.get(deployment) {
+        return Some((*limits, Source::Config));
+    }
+
+    KNOWN_MODELS
+        .iter()
+        .filter(|(name, _)| starts_with_model(deployment, name))
+        .max_by_key(|(name, _)| name.len())
+        .map(|(name, limits)| (*limits, Source::BuiltIn(name)))
+}
+
+/// Whether `deployment` starts with the model `name`, ignoring case and punctuation so that
+/// `gpt4o` matches `gpt-4o`. The match has to end where a word of the deployment name ends,
+/// so `gpt-4-1106-preview` doesn't match `gpt-4.1`.
+fn starts_with_model(deployment: &str, name: &str) -> bool {
+    let mut rest = deployment.chars();
+    for wanted in name.chars().filter(char::is_ascii_alphanumeric) {
+        let next = rest.find(char::is_ascii_alphanumeric);
+        if !next.is_some_and(|c| c.eq_ignore_ascii_case(&wanted)) {
+            return false;
+        }
+    }
+    !rest.next().is_some_and(|c| c.is_ascii_alphanumeric())
+}
+
+pub fn show(deployment: &str, config: &Config) {
+    println!("Deployment: {}", deployment);
+    match lookup(deployment, config) {
+        Some((limits, source)) => {
+            match source {
+                Source::Config => println!("Source: [models] table in ai.toml"),
+                Source::BuiltIn(name) => println!("Source: built-in limits for {}", name),
+            }
+            println!("Context window: {} tokens", limits.context_window);
+            println!("Max output: {} tokens", limits.max_output_tokens);
+        }
+        None => {
+            println!("No limits are known for this deployment.");
+            println!("Add them to the [models] table in ai.toml, for example:");
+            println!("\n[models.{}]\ncontext_window = 128000\nmax_output_tokens = 16384", deployment);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn builtin(deployment: &str) -> Option<&'static str> {
+        match lookup(deployment, &Config::default()) {
+            Some((_, Source::BuiltIn(name))) => Some(name),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn matches_whole_words_of_the_deployment_name() {
+        assert_eq!(builtin("gpt-4-1106-preview"), Some("gpt-4-1106"));
+        assert_eq!(builtin("gpt-4-0613"), Some("gpt-4"));
+        assert_eq!(builtin("gpt-4.1-2025-04-14"), Some("gpt-4.1"));
+        assert_eq!(builtin("gpt4o-mini"), Some("gpt-4o-mini"));
+        assert_eq!(builtin("GPT-4o-2024-08-06"), Some("gpt-4o"));
+        assert_eq!(builtin("gpt4prod"), None);
+    }
+}
@@ -0,0 +1,106 @@
+use serde_json::Value;
+
+pub const PLACEHOLDERS: &[&str] = &[
+    "content",
+    "prompt_tokens",
+    "completion_tokens",
+    "total_tokens",
+    "model",
+    "finish_reason",
+];
+
+enum Part<'a> {
+    Literal(String),
+    Placeholder(&'a str),
+}
+
+/// Splits a template into literal text and `{placeholder}` references. `{{` and `}}`
+/// produce literal braces, and `\n`, `\t`, and `\\` are unescaped so templates can be
+/// written on a single shell line.
+fn parse(template: &str) -> Result<Vec<Part<'_>>, String> {
+    let mut parts = Vec::new();
+    let mut literal = String::new();
+    let mut chars = template.char_indices().peekable();
+
+    while let Some((index, c)) = chars.next() {
+        match c {
+            '{' if chars.peek().map(|&(_, next)| next) == Some('{') => {
+                chars.next();
+                literal.push('{');
+            }
+            '}' if chars.peek().map(|&(_, next)| next) == Some('}') => {
+                chars.next();
+                literal.push('}');
+            }
+            '{' => {
+                let rest = &template[index + 1..];
+                let Some(end) = rest.find('}') else {
+                    return Err(format!("unclosed '{{' at position {}", index));
+                };
+                let name = &rest[..end];
+                if !PLACEHOLDERS.contains(&name) {
+                    return Err(format!("unknown placeholder '{{{}}}'. Available placeholders: {}", name, PLACEHOLDERS.join(", ")));
+                }
+                if !literal.is_empty() {
+                    parts.push(Part::Literal(std::mem::take(&mut literal)));
+                }
+                parts.push(Part::Placeholder(name));
+                for (_, c) in chars.by_ref() {
+                    if c == '}' {
+                        break;
+                    }
+                }
+            }
+            '}' => return Err(format!("unmatched '}}' at position {}", index)),
+            '\\' => {
+                let unescaped = match chars.peek().map(|&(_, next)| next) {
+                    Some('n') => Some('\n'),
+                    Some('t') => Some('\t'),
+                    Some('\\') => Some('\\'),
+                    _ => None,
+                };
+                match unescaped {
+                    Some(u) => {
+                        chars.next();
+                        literal.push(u);
+                    }
+                    None => literal.push('\\'),
+                }
+            }
+            _ => literal.push(c),
+        }
+    }
+
+    if !literal.is_empty() {
+        parts.push(Part::Literal(literal));
+    }
+    Ok(parts)
+}
+
+/// Checks a template for unknown placeholders and malformed braces.
+pub fn validate(template: &str) -> Result<(), String> {
+    parse(template).map(|_| ())
+}
+
+/// Fills in a template from the response. Fields the response doesn't include are left empty.
+pub fn render(template: &str, response_json: &Value, content: &str) -> Result<String, String> {
+    let mut output = String::new();
+    for part in parse(template)? {
+        match part {
+            Part::Literal(text) => output.push_str(&text),
+            Part::Placeholder("content") => output.push_str(content),
+            Part::Placeholder("finish_reason") => output.push_str(&field(&response_json["choices"][0]["finish_reason"])),
+            Part::Placeholder("model") => output.push_str(&field(&response_json["model"])),
+            Part::Placeholder(usage) => output.push_str(&field(&response_json["usage"][usage])),
+        }
+    }
+    Ok(output)
+}
+
+fn field(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
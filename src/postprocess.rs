@@ -0,0 +1,158 @@
+/// Removes every `open`...`close` block from `content`, returning the remaining text and
+/// the text of each removed block. An unclosed block runs to the end of the content.
+pub fn strip_thinking(content: &str, open: &str, close: &str) -> (String, Vec<String>) {
+    let mut remaining = String::new();
+    let mut thoughts = Vec::new();
+    let mut rest = content;
+
+    while let Some(start) = rest.find(open) {
+        remaining.push_str(&rest[..start]);
+        let after_open = &rest[start + open.len()..];
+        match after_open.find(close) {
+            Some(end) => {
+                thoughts.push(after_open[..end].trim().to_string());
+                rest = &after_open[end + close.len()..];
+            }
+            None => {
+                thoughts.push(after_open.trim().to_string());
+                rest = "";
+            }
+        }
+    }
+    remaining.push_str(rest);
+
+    (remaining.trim().to_string(), thoughts)
+}
+
+/// How trailing offers of further help usually begin, in lower case.
+const FILLER_OPENINGS: &[&str] = &[
+    "is there anything else",
+    "let me know if",
+    "let me know what",
+    "feel free to",
+    "i hope this helps",
+    "hope this helps",
+    "i hope that helps",
+    "hope that helps",
+    "if you have any other questions",
+    "if you have any more questions",
+    "if you have any further questions",
+    "if you have any questions",
+    "if you need anything else",
+    "if you need any further",
+    "if you need more",
+    "if you'd like, i can",
+    "would you like me to",
+    "happy to help",
+    "i'm happy to help",
+    "i'd be happy to help",
+];
+
+/// Paragraphs longer than this are assumed to be part of the answer, even if they start like filler.
+const MAX_FILLER_LENGTH: usize = 250;
+
+/// Removes trailing paragraphs that only offer further help ("Let me know if you need
+/// anything else!"), returning the remaining text and what was removed. Only short final
+/// paragraphs that begin with a known phrase are removed, and never the whole response.
+pub fn strip_filler(content: &str) -> (String, Vec<String>) {
+    let mut remaining = content.trim_end();
+    let mut removed = Vec::new();
+
+    while let Some(split) = remaining.rfind("\n\n") {
+        let paragraph = remaining[split..].trim();
+        let lower = paragraph.to_lowercase();
+        let lower = lower.trim_start_matches(|c: char| !c.is_alphanumeric());
+        if paragraph.len() > MAX_FILLER_LENGTH || !FILLER_OPENINGS.iter().any(|opening| lower.starts_with(opening)) {
+            break;
+        }
+        removed.insert(0, paragraph.to_string());
+        remaining = remaining[..split].trim_end();
+    }
+
+    (remaining.to_string(), removed)
+}
+
+pub enum JsonStyle {
+    Unchanged,
+    Pretty,
+    Minified,
+}
+
+/// Checks that `content` is a single JSON value, and reformats it in the given style.
+pub fn check_json(content: &str, style: JsonStyle) -> Result<String, serde_json::Error> {
+    let value: serde_json::Value = serde_json::from_str(content)?;
+    match style {
+        JsonStyle::Unchanged => Ok(content.to_string()),
+        JsonStyle::Pretty => serde_json::to_string_pretty(&value),
+        JsonStyle::Minified => serde_json::to_string(&value),
+    }
+}
+
+/// Turns near-valid JSON, such as JSON with trailing commas, comments, single quotes, or
+/// unquoted keys, or JSON inside a Markdown code fence, into strict JSON. Returns `None`
+/// when `content` is strict JSON already.
+pub fn repair_json(content: &str) -> Result<Option<String>, String> {
+    if serde_json::from_str::<serde_json::Value>(content).is_ok() {
+        return Ok(None);
+    }
+    let trimmed = content.trim();
+    let unfenced = trimmed
+        .strip_prefix("```")
+        .and_then(|rest| rest.strip_suffix("```"))
+        .map(|inner| inner.trim_start_matches(|c: char| c.is_ascii_alphanumeric()).trim())
+        .unwrap_or(trimmed);
+    // json5 errors draw the input with a caret; keep only the explanation at the end
+    let value: serde_json::Value = json5::from_str(unfenced).map_err(|e| e.to_string().lines().last().unwrap_or_default().trim().trim_start_matches("= ").to_string())?;
+    serde_json::to_string_pretty(&value).map(Some).map_err(|e| e.to_string())
+}
+
+/// Parses an --assert-matches regular expression.
+pub fn parse_regex(pattern: &str) -> Result<regex::Regex, String> {
+    regex::Regex::new(pattern).map_err(|e| format!("invalid regular expression '{}': {}", pattern, e))
+}
+
+/// Checks `content` against every --assert-contains text and --assert-matches pattern, and
+/// describes each one it fails.
+pub fn check_assertions(content: &str, contains: &[String], matches: &[regex::Regex]) -> Vec<String> {
+    let missing = contains.iter().filter(|text| !content.contains(text.as_str())).map(|text| format!("the response does not contain '{}'", text));
+    let unmatched = matches.iter().filter(|pattern| !pattern.is_match(content)).map(|pattern| format!("the response does not match /{}/", pattern));
+    missing.chain(unmatched).collect()
+}
+
+/// Parses a JSONPath expression such as `$.items[0].name`. The leading `$` can be left off.
+pub fn parse_json_path(path: &str) -> Result<serde_json_path::JsonPath, String> {
+    let full = if path.starts_with('.') || path.starts_with('[') { format!("${}", path) } else { path.to_string() };
+    serde_json_path::JsonPath::parse(&full).map_err(|e| format!("invalid JSON path '{}': {}", path, e))
+}
+
+/// Extracts the values at `path` from JSON `content`, one per line. Strings are printed
+/// without quotes so single fields can be used directly in shell pipelines.
+pub fn extract_json_path(content: &str, path: &serde_json_path::JsonPath) -> Result<String, String> {
+    let value: serde_json::Value = serde_json::from_str(content).map_err(|e| format!("the response is not valid JSON: {}", e))?;
+    let matches = path.query(&value).all();
+    if matches.is_empty() {
+        return Err(format!("the JSON path '{}' did not match anything in the response", path));
+    }
+
+    let lines: Vec<String> = matches
+        .into_iter()
+        .map(|value| match value {
+            serde_json::Value::String(s) => s.clone(),
+            other => serde_json::to_string_pretty(other).unwrap_or_default(),
+        })
+        .collect();
+    Ok(lines.join("\n"))
+}
+
+/// Parses a --response-language value: an ISO 639-3 code such as `fra`, or an English name such as `French`.
+pub fn parse_language(arg: &str) -> Result<whatlang::Lang, String> {
+    let arg = arg.trim().to_lowercase();
+    whatlang::Lang::from_code(arg.as_str())
+        .or_else(|| whatlang::Lang::all().iter().copied().find(|lang| lang.eng_name().to_lowercase() == arg))
+        .ok_or_else(|| format!("'{}' isn't a language code (such as fra or deu) or language name that can be detected", arg))
+}
+
+/// Guesses the language of `content`, or `None` when the text is too short or mixed to tell reliably.
+pub fn detect_language(content: &str) -> Option<whatlang::Lang> {
+    whatlang::detect(content).filter(|info| info.is_reliable()).map(|info| info.lang())
+}
@@ -0,0 +1,144 @@
+/// How a --prompt combines with the project prompt (`.aiprompt`) and any --preset.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum PromptMode {
+    /// Use only the --prompt
+    Replace,
+    /// Put the --prompt first, then the project prompt and preset
+    Prepend,
+    /// Put the project prompt and preset first, then the --prompt
+    Append,
+}
+
+impl PromptMode {
+    /// Combines `prompt` with the other prompts in effect, which are given in order.
+    pub fn combine(self, prompt: String, others: &[&str]) -> String {
+        let others = others.iter().map(|other| other.trim_end());
+        match self {
+            PromptMode::Replace => prompt,
+            PromptMode::Prepend => std::iter::once(prompt.trim_end()).chain(others).collect::<Vec<_>>().join("\n\n"),
+            PromptMode::Append => others.chain(std::iter::once(prompt.as_str())).collect::<Vec<_>>().join("\n\n"),
+        }
+    }
+}
+
+pub struct Preset {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub prompt: &'static str,
+}
+
+pub const PRESETS: &[Preset] = &[
+    Preset {
+        name: "summarize",
+        description: "Summarize the input concisely",
+        prompt: "You are an AI assistant that summarizes text. Produce a concise summary of the input \
+that captures its key points, decisions, and conclusions. Use short paragraphs or bullet points, \
+and do not add information that is not present in the input.",
+    },
+    Preset {
+        name: "explain",
+        description: "Explain the input in plain language",
+        prompt: "You are an AI assistant that explains things clearly. Explain the input in plain \
+language for a technically literate reader who is unfamiliar with the subject. Define jargon when \
+you first use it, and walk through anything complex step by step.",
+    },
+    Preset {
+        name: "translate",
+        description: "Translate the input into English (or the language requested in the input)",
+        prompt: "You are an AI assistant that translates text. Translate the input into English, \
+unless the input asks for a different target language. Preserve the meaning, tone, and formatting \
+of the original, and output only the translation.",
+    },
+    Preset {
+        name: "proofread",
+        description: "Correct spelling, grammar, and clarity",
+        prompt: "You are an AI assistant that proofreads text. Correct spelling, grammar, and \
+punctuation mistakes in the input and improve clarity where it is awkward, while keeping the \
+author's voice and meaning. Output only the corrected text.",
+    },
+    Preset {
+        name: "extract-action-items",
+        description: "List the action items, owners, and due dates in the input",
+        prompt: "You are an AI assistant that extracts action items. Read the input (for example \
+meeting notes, an email thread, or a transcript) and list every action item as a bullet point. \
+Include the owner and due date for each item when they are mentioned. If there are no action \
+items, say so.",
+    },
+    Preset {
+        name: "review-changes",
+        description: "Review a unified diff (used by --diff-input)",
+        prompt: "You are an AI assistant that reviews changes. The input is a unified diff. \
+Summarize what changed and why it appears to have changed, then point out any mistakes, risks, or \
+inconsistencies the changes introduce, referring to the affected lines. Do not comment on lines \
+that did not change.",
+    },
+];
+
+pub fn find(name: &str) -> Option<&'static Preset> {
+    PRESETS.iter().find(|preset| preset.name == name)
+}
+
+pub fn list() {
+    println!("Available presets:");
+    for preset in PRESETS {
+        println!("    {:<22}{}", preset.name, preset.description);
+    }
+}
+
+/// A named character for casual use. Its system prompt introduces it by name.
+pub struct Persona {
+    pub name: &'static str,
+    pub character: &'static str,
+    pub description: &'static str,
+}
+
+impl Persona {
+    pub fn prompt(&self) -> String {
+        format!("You are {}, {}", self.character, self.description)
+    }
+}
+
+pub const PERSONAS: &[Persona] = &[
+    Persona {
+        name: "pirate",
+        character: "Captain Redbeard",
+        description: "a cheerful pirate. Answer every question accurately and helpfully, but speak \
+like a pirate and work in the occasional nautical metaphor.",
+    },
+    Persona {
+        name: "tutor",
+        character: "Professor Ada",
+        description: "a patient tutor. Rather than just giving answers, guide the user to \
+understand them: explain the reasoning step by step, check their understanding, and encourage them.",
+    },
+    Persona {
+        name: "critic",
+        character: "Morgan",
+        description: "a blunt but fair reviewer. Point out the weaknesses, risks, and mistakes in \
+what the user shares, say what is good about it too, and suggest specific improvements.",
+    },
+    Persona {
+        name: "bard",
+        character: "the Bard",
+        description: "a poet in the style of Shakespeare. Answer accurately, in Early Modern English \
+verse where it suits the question.",
+    },
+    Persona {
+        name: "coach",
+        character: "Coach Riley",
+        description: "an upbeat coach. Help the user break goals into practical next steps, keep \
+them motivated, and be honest about what will take effort.",
+    },
+];
+
+pub fn find_persona(name: &str) -> Option<&'static Persona> {
+    PERSONAS.iter().find(|persona| persona.name == name)
+}
+
+pub fn list_personas() {
+    println!("Available personas:");
+    for persona in PERSONAS {
+        let summary = persona.description.split('.').next().unwrap_or_default();
+        println!("    {:<22}{}, {}", persona.name, persona.character, summary);
+    }
+}
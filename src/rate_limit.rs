@@ -0,0 +1,56 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use log::debug;
+use tokio::time::Instant;
+
+/// The longest wait between two requests, however low the limit
+const MAX_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Whether `per_minute` can be used as a rate limit.
+pub fn is_valid(per_minute: f64) -> bool {
+    per_minute.is_finite() && per_minute > 0.0
+}
+
+/// Parses a --rate-limit value, in requests per minute.
+pub fn parse(arg: &str) -> Result<f64, String> {
+    match arg.parse::<f64>() {
+        Ok(per_minute) if is_valid(per_minute) => Ok(per_minute),
+        Ok(_) => Err(format!("the rate limit must be a positive number of requests per minute, got '{}'", arg)),
+        Err(_) => Err(format!("expected a number, got '{}'", arg)),
+    }
+}
+
+/// Spaces out the requests to each deployment so that none is sent more than its
+/// requests-per-minute limit, from the `[rate_limits]` table or --rate-limit.
+pub struct RateLimiter {
+    /// Requests per minute, by deployment. Deployments without an entry aren't limited.
+    limits: HashMap<String, f64>,
+    /// When each limited deployment can next be sent a request
+    next_slot: Mutex<HashMap<String, Instant>>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: HashMap<String, f64>) -> Self {
+        RateLimiter { limits, next_slot: Mutex::new(HashMap::new()) }
+    }
+
+    /// Waits until `deployment` can be sent another request, and takes that turn.
+    pub async fn wait(&self, deployment: &str) {
+        let Some(&per_minute) = self.limits.get(deployment) else {
+            return;
+        };
+        let interval = Duration::try_from_secs_f64(60.0 / per_minute).map_or(MAX_INTERVAL, |interval| interval.min(MAX_INTERVAL));
+        let now = Instant::now();
+        let slot = {
+            let mut next_slot = self.next_slot.lock().unwrap_or_else(|e| e.into_inner());
+            let slot = next_slot.get(deployment).copied().filter(|&slot| slot > now).unwrap_or(now);
+            next_slot.insert(deployment.to_string(), slot + interval);
+            slot
+        };
+        if slot > now {
+            debug!("Waiting {:?} to stay within the {} requests per minute limit of '{}'", slot - now, per_minute, deployment);
+            tokio::time::sleep_until(slot).await;
+        }
+    }
+}
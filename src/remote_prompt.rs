@@ -0,0 +1,42 @@
+use std::path::PathBuf;
+use std::time::Duration;
+use log::debug;
+use sha2::{Digest, Sha256};
+
+/// How long a fetched prompt is used before it is fetched again
+const CACHE_TIME: Duration = Duration::from_secs(5 * 60);
+
+/// Whether a --prompt or --messages argument names a URL rather than a file or text.
+pub fn is_url(arg: &str) -> bool {
+    arg.starts_with("https://") || arg.starts_with("http://")
+}
+
+fn cache_path(url: &str) -> Option<PathBuf> {
+    let name = format!("{:x}", Sha256::digest(url.as_bytes()));
+    crate::config::config_dir().map(|dir| dir.join("prompt_cache").join(name))
+}
+
+/// Downloads `url` into the prompt cache and returns the cached file, so it can be read
+/// like any other prompt file. A copy fetched in the last few minutes is used as it is.
+pub async fn fetch(client: &reqwest::Client, url: &str) -> Result<PathBuf, String> {
+    let path = cache_path(url).ok_or("could not determine the config directory")?;
+    let age = std::fs::metadata(&path).and_then(|metadata| metadata.modified()).ok().and_then(|modified| modified.elapsed().ok());
+    if age.is_some_and(|age| age < CACHE_TIME) {
+        debug!("Using the copy of {} fetched {:?} ago", url, age.unwrap_or_default());
+        return Ok(path);
+    }
+
+    let response = client.get(url).send().await.map_err(|e| format!("could not fetch '{}': {}", url, e))?;
+    let status = response.status();
+    if !status.is_success() {
+        return Err(format!("could not fetch '{}': HTTP {}", url, status));
+    }
+    let body = response.bytes().await.map_err(|e| format!("could not fetch '{}': {}", url, e))?;
+    debug!("Fetched {} bytes from {}", body.len(), url);
+
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).map_err(|e| format!("could not create {}: {}", dir.display(), e))?;
+    }
+    std::fs::write(&path, &body).map_err(|e| format!("could not write {}: {}", path.display(), e))?;
+    Ok(path)
+}
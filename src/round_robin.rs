@@ -0,0 +1,51 @@
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use log::{debug, warn};
+
+/// Spreads requests across the `round_robin` deployments in ai.toml, remembering which
+/// one is next between runs.
+pub struct RoundRobin {
+    /// How many of the deployments, from the start of the list, take turns
+    count: usize,
+    next: AtomicUsize,
+    state_path: Option<PathBuf>,
+}
+
+fn state_path() -> Option<PathBuf> {
+    crate::config::config_dir().map(|dir| dir.join("round_robin"))
+}
+
+impl RoundRobin {
+    pub fn load(count: usize) -> Self {
+        let state_path = state_path();
+        let next = state_path
+            .as_ref()
+            .and_then(|path| std::fs::read_to_string(path).ok())
+            .and_then(|contents| contents.trim().parse().ok())
+            .unwrap_or(0);
+        RoundRobin { count, next: AtomicUsize::new(next), state_path }
+    }
+
+    /// The deployment whose turn it is, without moving the turn on.
+    pub fn peek<'a>(&self, deployments: &'a [String]) -> &'a str {
+        &deployments[self.next.load(Ordering::Relaxed) % self.count]
+    }
+
+    /// Returns `deployments` starting from the one whose turn it is, and moves the turn on.
+    /// Deployments after the round-robin ones (such as --fallback-deployment) stay last.
+    pub fn next_order(&self, deployments: &[String]) -> Vec<String> {
+        let start = self.next.fetch_add(1, Ordering::Relaxed) % self.count;
+        if let Some(path) = &self.state_path {
+            let saved = std::fs::create_dir_all(path.parent().unwrap_or(path)).and_then(|()| std::fs::write(path, ((start + 1) % self.count).to_string()));
+            if let Err(e) = saved {
+                warn!(code = "round_robin_state"; "Could not save the round-robin position to {}: {}", path.display(), e);
+            }
+        }
+
+        let (rotating, rest) = deployments.split_at(self.count);
+        let mut order: Vec<String> = rotating[start..].iter().chain(&rotating[..start]).cloned().collect();
+        order.extend_from_slice(rest);
+        debug!("Round-robin picked deployment '{}'", order[0]);
+        order
+    }
+}
@@ -0,0 +1,62 @@
+use serde_json::{json, Value};
+
+/// A JSON Schema that responses are constrained to and checked against.
+pub struct ResponseSchema {
+    schema: Value,
+    validator: jsonschema::Validator,
+}
+
+impl ResponseSchema {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let contents = crate::read_input_to_string(path).map_err(|e| format!("could not read schema file '{}': {}", path, e))?;
+        let schema: Value = serde_json::from_str(&contents).map_err(|e| format!("schema file '{}' is not valid JSON: {}", path, e))?;
+        let validator = jsonschema::validator_for(&schema).map_err(|e| format!("schema file '{}' is not a valid JSON Schema: {}", path, e))?;
+        Ok(ResponseSchema { schema, validator })
+    }
+
+    /// The `response_format` that asks the service for structured output matching the schema.
+    pub fn response_format(&self) -> Value {
+        json!({
+            "type": "json_schema",
+            "json_schema": {
+                "name": "response",
+                "strict": true,
+                "schema": self.schema,
+            }
+        })
+    }
+
+    /// An instruction for services that don't support structured output, so the model
+    /// still knows what shape to produce.
+    pub fn instructions(&self) -> String {
+        format!(
+            "Respond only with a JSON value that conforms to this JSON Schema, with no other text:\n{}",
+            serde_json::to_string_pretty(&self.schema).unwrap_or_default()
+        )
+    }
+
+    /// Checks that `content` is JSON conforming to the schema, returning each problem found.
+    pub fn validate(&self, content: &str) -> Result<(), Vec<String>> {
+        let instance: Value = serde_json::from_str(content).map_err(|e| vec![format!("the response is not valid JSON: {}", e)])?;
+        let errors: Vec<String> = self
+            .validator
+            .iter_errors(&instance)
+            .map(|error| {
+                let path = error.instance_path().to_string();
+                if path.is_empty() { error.to_string() } else { format!("{}: {}", path, error) }
+            })
+            .collect();
+        if errors.is_empty() { Ok(()) } else { Err(errors) }
+    }
+}
+
+/// Whether an error response means the service rejected the `response_format` itself,
+/// rather than something else about the request.
+pub fn is_unsupported_response_format(status: reqwest::StatusCode, response_json: &Value) -> bool {
+    if status != reqwest::StatusCode::BAD_REQUEST {
+        return false;
+    }
+    let error = &response_json["error"];
+    let mentions = |field: &Value| field.as_str().is_some_and(|text| text.contains("response_format") || text.contains("json_schema"));
+    mentions(&error["message"]) || mentions(&error["param"])
+}
@@ -0,0 +1,146 @@
+use std::fs::File;
+use std::io::{self, Write};
+use encoding_rs::{EncoderResult, WINDOWS_1252};
+use log::warn;
+
+/// Text encodings the --output file can be written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Encoding {
+    Utf8,
+    /// UTF-16 little endian, with a byte order mark
+    Utf16le,
+    /// ISO-8859-1 (as Windows-1252); characters it can't represent are written as '?'
+    Latin1,
+}
+
+/// Where the response is written: stdout, an --output file, or both.
+pub struct Sink {
+    stdout: Option<io::Stdout>,
+    file: Option<File>,
+    encoding: Encoding,
+    /// The start of a UTF-8 sequence split across writes, held until the rest arrives
+    pending: Vec<u8>,
+    warned_unmappable: bool,
+    /// With --truncate-output, the most characters to print to stdout
+    stdout_limit: Option<usize>,
+    /// How many characters have been printed to stdout
+    stdout_chars: usize,
+}
+
+impl Sink {
+    pub fn new(path: Option<&str>, to_stdout: bool, encoding: Encoding, stdout_limit: Option<usize>) -> Result<Self, String> {
+        let file = match path {
+            Some(path) => {
+                let mut file = File::create(path).map_err(|e| format!("could not create output file '{}': {}", path, e))?;
+                if encoding == Encoding::Utf16le {
+                    file.write_all(&[0xFF, 0xFE]).map_err(|e| format!("could not write output file '{}': {}", path, e))?;
+                }
+                Some(file)
+            }
+            None => None,
+        };
+        Ok(Sink {
+            stdout: to_stdout.then(io::stdout),
+            file,
+            encoding,
+            pending: Vec::new(),
+            warned_unmappable: false,
+            stdout_limit,
+            stdout_chars: 0,
+        })
+    }
+
+    /// Converts UTF-8 response text to the file's encoding.
+    fn encode(&mut self, text: &str) -> Vec<u8> {
+        match self.encoding {
+            Encoding::Utf8 => text.as_bytes().to_vec(),
+            Encoding::Utf16le => text.encode_utf16().flat_map(u16::to_le_bytes).collect(),
+            Encoding::Latin1 => {
+                let mut encoder = WINDOWS_1252.new_encoder();
+                let mut encoded = Vec::with_capacity(text.len());
+                let mut buffer = [0u8; 1024];
+                let mut rest = text;
+                loop {
+                    let (result, read, written) = encoder.encode_from_utf8_without_replacement(rest, &mut buffer, true);
+                    encoded.extend_from_slice(&buffer[..written]);
+                    rest = &rest[read..];
+                    match result {
+                        EncoderResult::InputEmpty => break,
+                        EncoderResult::OutputFull => {}
+                        EncoderResult::Unmappable(c) => {
+                            encoded.push(b'?');
+                            if !self.warned_unmappable {
+                                warn!(code = "unmappable_character"; "The response contains characters latin1 can't represent, such as '{}'; they are written as '?'", c);
+                                self.warned_unmappable = true;
+                            }
+                        }
+                    }
+                }
+                encoded
+            }
+        }
+    }
+
+    /// Writes to stdout, up to the --truncate-output limit. Whole characters are counted
+    /// by their first byte, so a character split across writes is never cut in two, and line
+    /// breaks aren't counted, so a response that just fits keeps its final newline. Once the
+    /// limit is reached, an ellipsis and a newline end the output and the rest is dropped.
+    fn write_stdout(&mut self, buf: &[u8]) -> io::Result<()> {
+        let Some(stdout) = &mut self.stdout else {
+            return Ok(());
+        };
+        let Some(limit) = self.stdout_limit else {
+            return stdout.write_all(buf);
+        };
+        if self.stdout_chars > limit {
+            return Ok(());
+        }
+        for (index, &byte) in buf.iter().enumerate() {
+            let starts_char = byte & 0xC0 != 0x80 && byte != b'\n' && byte != b'\r';
+            if starts_char && self.stdout_chars == limit {
+                stdout.write_all(&buf[..index])?;
+                stdout.write_all("…\n".as_bytes())?;
+                self.stdout_chars += 1;
+                return Ok(());
+            }
+            if starts_char {
+                self.stdout_chars += 1;
+            }
+        }
+        stdout.write_all(buf)
+    }
+}
+
+impl Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_stdout(buf)?;
+        if self.file.is_some() {
+            let bytes = if self.encoding == Encoding::Utf8 {
+                buf.to_vec()
+            } else {
+                self.pending.extend_from_slice(buf);
+                let valid = match std::str::from_utf8(&self.pending) {
+                    Ok(_) => self.pending.len(),
+                    Err(e) if e.error_len().is_none() => e.valid_up_to(),
+                    Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+                };
+                let complete: Vec<u8> = self.pending.drain(..valid).collect();
+                self.encode(std::str::from_utf8(&complete).unwrap_or_default())
+            };
+            if let Some(file) = &mut self.file {
+                file.write_all(&bytes)?;
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if let Some(stdout) = &mut self.stdout {
+            stdout.flush()?;
+        }
+        if let Some(file) = &mut self.file {
+            file.flush()?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,132 @@
+use std::io::{self, BufWriter, Write};
+use std::time::Duration;
+use log::{debug, trace};
+use serde_json::{json, Value};
+
+/// Writes streamed text to the output, flushing once at least `flush_every` characters
+/// have been written since the last flush. A `flush_every` of 0 only flushes at the end.
+struct StreamWriter<'a, W: Write> {
+    out: BufWriter<&'a mut W>,
+    flush_every: usize,
+    pending: usize,
+}
+
+impl<'a, W: Write> StreamWriter<'a, W> {
+    fn new(sink: &'a mut W, flush_every: usize) -> Self {
+        StreamWriter {
+            out: BufWriter::new(sink),
+            flush_every,
+            pending: 0,
+        }
+    }
+
+    fn write(&mut self, text: &str) -> io::Result<()> {
+        self.out.write_all(text.as_bytes())?;
+        self.pending += text.chars().count();
+        if self.flush_every > 0 && self.pending >= self.flush_every {
+            self.out.flush()?;
+            self.pending = 0;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self, suffix: &str) -> io::Result<()> {
+        self.out.write_all(suffix.as_bytes())?;
+        self.out.flush()
+    }
+}
+
+/// The stream stopped sending data for longer than the idle timeout.
+#[derive(Debug)]
+pub struct Stalled(pub Duration);
+
+impl std::fmt::Display for Stalled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "the stream stalled: nothing arrived for {:?}", self.0)
+    }
+}
+
+impl std::error::Error for Stalled {}
+
+/// Writes a server-sent event stream of chat completion chunks to `sink` (usually the
+/// output `Sink`) as they arrive, between the `(prefix, suffix)` framing (the suffix
+/// including any final newline), and returns the assembled response in the same shape as
+/// a non-streaming one. If `idle_timeout` passes without any data arriving, the partial
+/// output is finished off and a `Stalled` error is returned.
+pub async fn read_stream(mut response: reqwest::Response, flush_every: usize, sink: &mut impl Write, framing: (&str, &str), idle_timeout: Option<Duration>) -> Result<Value, Box<dyn std::error::Error>> {
+    let (prefix, suffix) = framing;
+    let mut writer = StreamWriter::new(sink, flush_every);
+    writer.write(prefix)?;
+    let mut buffer: Vec<u8> = Vec::new();
+    let mut content = String::new();
+    let mut refusal = String::new();
+    let mut finish_reason = Value::Null;
+    let mut usage = Value::Null;
+
+    'stream: loop {
+        let chunk = match idle_timeout {
+            Some(idle) => match tokio::time::timeout(idle, response.chunk()).await {
+                Ok(chunk) => chunk?,
+                Err(_) => {
+                    writer.finish(suffix)?;
+                    return Err(Stalled(idle).into());
+                }
+            },
+            None => response.chunk().await?,
+        };
+        let Some(chunk) = chunk else {
+            break;
+        };
+        buffer.extend_from_slice(&chunk);
+
+        // Events are newline-delimited; keep any partial line for the next chunk
+        while let Some(newline) = buffer.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = buffer.drain(..=newline).collect();
+            let line = String::from_utf8_lossy(&line);
+            let Some(data) = line.trim().strip_prefix("data:") else {
+                continue;
+            };
+
+            let data = data.trim();
+            trace!("Stream event: {}", data);
+            if data == "[DONE]" {
+                break 'stream;
+            }
+
+            let event: Value = serde_json::from_str(data)?;
+
+            // With stream_options.include_usage, the last chunk has the usage and no choices
+            if !event["usage"].is_null() {
+                usage = event["usage"].clone();
+            }
+
+            // Azure sends content filter results in chunks with no choices
+            let Some(choice) = event["choices"].get(0) else {
+                continue;
+            };
+
+            if let Some(text) = choice["delta"]["content"].as_str() {
+                writer.write(text)?;
+                content.push_str(text);
+            }
+            if let Some(text) = choice["delta"]["refusal"].as_str() {
+                refusal.push_str(text);
+            }
+            if !choice["finish_reason"].is_null() {
+                finish_reason = choice["finish_reason"].clone();
+            }
+        }
+    }
+
+    writer.finish(suffix)?;
+    debug!("Stream finished with {} characters of content", content.chars().count());
+
+    let refusal = if refusal.is_empty() { Value::Null } else { Value::String(refusal) };
+    Ok(json!({
+        "choices": [{
+            "message": { "role": "assistant", "content": content, "refusal": refusal },
+            "finish_reason": finish_reason,
+        }],
+        "usage": usage,
+    }))
+}
@@ -0,0 +1,57 @@
+use futures::stream::{self, StreamExt};
+use serde_json::{json, Value};
+use crate::{Api, ChatRequest};
+
+/// The temperature as written, rather than its nearest f64 (0.7 rather than 0.699999988).
+fn number(temperature: f32) -> Value {
+    temperature.to_string().parse::<f64>().map(Value::from).unwrap_or(Value::Null)
+}
+
+/// Sends `template` once per temperature, at most `concurrency` at a time, and prints each
+/// answer under a label with its temperature. With `json_array`, prints one JSON array of
+/// `{temperature, content, usage}` results instead, with an "error" field for failures.
+pub async fn run(api: &Api, template: &ChatRequest, temperatures: &[f32], json_array: bool, concurrency: usize) -> Result<(), Box<dyn std::error::Error>> {
+    let answers: Vec<(f32, Result<Value, String>)> = stream::iter(temperatures)
+        .map(|&temperature| async move {
+            let mut request = template.clone();
+            request.temperature = temperature;
+            api.print_hash(&request, Some(&format!("temperature {}", temperature)));
+            let answer = api.complete_json(&request).await.map_err(|e| e.to_string());
+            (temperature, answer)
+        })
+        .buffered(concurrency.max(1))
+        .collect()
+        .await;
+
+    let mut results = Vec::new();
+    let mut failures = 0;
+    for (temperature, answer) in answers {
+        if !json_array {
+            println!("===== temperature {} =====", temperature);
+        }
+        if let Err(e) = &answer {
+            crate::diagnostics::error("sweep_failed", format!("temperature {}: {}", temperature, e));
+            failures += 1;
+        }
+        match answer {
+            Ok(response_json) if json_array => results.push(json!({
+                "temperature": number(temperature),
+                "content": response_json["choices"][0]["message"]["content"],
+                "usage": response_json["usage"],
+            })),
+            Err(e) if json_array => results.push(json!({ "temperature": number(temperature), "error": e })),
+            Ok(response_json) => println!("{}\n", response_json["choices"][0]["message"]["content"].as_str().unwrap_or_default().trim_end()),
+            Err(_) => {}
+        }
+    }
+
+    if json_array {
+        println!("{}", serde_json::to_string_pretty(&results)?);
+    }
+    api.report_retry_budget();
+    if failures > 0 {
+        crate::diagnostics::error("sweep_failed", format!("{} of {} temperature(s) failed.", failures, temperatures.len()));
+        std::process::exit(1);
+    }
+    Ok(())
+}
@@ -0,0 +1,24 @@
+use tiktoken_rs::CoreBPE;
+use crate::Message;
+
+/// Picks the tokenizer for a deployment. Azure deployment names are chosen by the user, so
+/// anything that isn't a recognizable model name gets the tokenizer used by GPT-4o.
+fn tokenizer(deployment: &str) -> &'static CoreBPE {
+    tiktoken_rs::bpe_for_model(deployment).unwrap_or_else(|_| tiktoken_rs::o200k_base_singleton())
+}
+
+/// Counts the tokens the messages will use as a prompt, including the few tokens of
+/// framing the service adds around each message. Image and file attachments aren't counted.
+pub fn count_prompt_tokens(messages: &[Message], deployment: &str) -> usize {
+    let bpe = tokenizer(deployment);
+    let per_message: usize = messages
+        .iter()
+        .map(|m| 3 + bpe.encode_ordinary(&m.role).len() + bpe.encode_ordinary(&m.text()).len())
+        .sum();
+    // Every reply is primed with <|start|>assistant<|message|>
+    per_message + 3
+}
+
+pub fn count_tokens(text: &str, deployment: &str) -> usize {
+    tokenizer(deployment).encode_ordinary(text).len()
+}
@@ -0,0 +1,83 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Loads template variables (or --alias-file names) from a TOML, JSON, or env-style
+/// (`NAME=value` lines) file, chosen by the file extension. Values that aren't strings are
+/// used in their JSON form.
+pub fn load_file(path: &str) -> Result<HashMap<String, String>, String> {
+    let read = || crate::read_input_to_string(path).map_err(|e| format!("could not read '{}': {}", path, e));
+    let invalid = |e: &dyn std::fmt::Display| format!("'{}' is not valid: {}", path, e);
+    let as_string = |value: serde_json::Value| match value {
+        serde_json::Value::String(s) => s,
+        other => other.to_string(),
+    };
+
+    match Path::new(path).extension().and_then(|ext| ext.to_str()) {
+        Some("toml") => {
+            let table: toml::Table = toml::from_str(&read()?).map_err(|e| invalid(&e))?;
+            Ok(table
+                .into_iter()
+                .map(|(name, value)| {
+                    let value = match value {
+                        toml::Value::String(s) => s,
+                        other => other.to_string(),
+                    };
+                    (name, value)
+                })
+                .collect())
+        }
+        Some("json") => {
+            let map: serde_json::Map<String, serde_json::Value> = serde_json::from_str(&read()?).map_err(|e| invalid(&e))?;
+            Ok(map.into_iter().map(|(name, value)| (name, as_string(value))).collect())
+        }
+        _ => read()?
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty() && !line.trim_start().starts_with('#'))
+            .map(|(index, line)| {
+                let line = line.trim().strip_prefix("export ").unwrap_or(line.trim());
+                match line.split_once('=') {
+                    Some((name, value)) if !name.trim().is_empty() => Ok((name.trim().to_string(), unquote(value.trim()).to_string())),
+                    _ => Err(format!("'{}' line {}: expected NAME=value", path, index + 1)),
+                }
+            })
+            .collect(),
+    }
+}
+
+fn unquote(value: &str) -> &str {
+    for quote in ['"', '\''] {
+        if let Some(inner) = value.strip_prefix(quote).and_then(|v| v.strip_suffix(quote)) {
+            return inner;
+        }
+    }
+    value
+}
+
+/// Replaces each `{{name}}` (spaces inside the braces are allowed) with the variable's
+/// value. Placeholders for variables that aren't defined are left as they are, and returned.
+pub fn expand(text: &str, variables: &HashMap<String, String>) -> (String, Vec<String>) {
+    let mut output = String::with_capacity(text.len());
+    let mut undefined = Vec::new();
+    let mut rest = text;
+
+    while let Some(start) = rest.find("{{") {
+        let Some(end) = rest[start + 2..].find("}}") else {
+            break;
+        };
+        let placeholder = &rest[start..start + 2 + end + 2];
+        let name = placeholder[2..placeholder.len() - 2].trim();
+        output.push_str(&rest[..start]);
+        match variables.get(name) {
+            Some(value) => output.push_str(value),
+            None => {
+                output.push_str(placeholder);
+                undefined.push(name.to_string());
+            }
+        }
+        rest = &rest[start + placeholder.len()..];
+    }
+    output.push_str(rest);
+
+    (output, undefined)
+}
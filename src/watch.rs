@@ -0,0 +1,90 @@
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use log::debug;
+use notify::{EventKind, RecursiveMode, Watcher};
+use tokio::sync::mpsc;
+
+/// How long to wait after a change for the rest of an editor's save to land
+const SETTLE_TIME: Duration = Duration::from_millis(150);
+
+/// The arguments this run was started with, without its --watch options, so each rerun
+/// sends the request once instead of watching again.
+fn rerun_args() -> Vec<OsString> {
+    let mut args = Vec::new();
+    let mut iter = std::env::args_os().skip(1);
+    while let Some(arg) = iter.next() {
+        match arg.to_str() {
+            Some("--watch") => {
+                iter.next();
+            }
+            Some(arg) if arg.starts_with("--watch=") => {}
+            _ => args.push(arg),
+        }
+    }
+    args
+}
+
+/// Waits for a change to one of `files`, then for the changes to settle.
+async fn changed(events: &mut mpsc::UnboundedReceiver<notify::Event>, files: &HashSet<PathBuf>) {
+    let touches = |event: &notify::Event| !matches!(event.kind, EventKind::Access(_)) && event.paths.iter().any(|path| files.contains(path));
+    while let Some(event) = events.recv().await {
+        if touches(&event) {
+            debug!("Watched file changed: {:?}", event.paths);
+            break;
+        }
+    }
+    tokio::time::sleep(SETTLE_TIME).await;
+    while events.try_recv().is_ok() {}
+}
+
+/// Runs the request again, as a fresh run of this program, each time one of `files`
+/// changes, clearing the screen first. Ctrl-C stops watching.
+pub async fn run(files: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let exe = std::env::current_exe()?;
+    let args = rerun_args();
+
+    // Editors often save by replacing the file, so watch the directories that hold the
+    // files rather than the files themselves
+    let mut watched = HashSet::new();
+    for file in files {
+        let path = std::fs::canonicalize(file).map_err(|e| format!("can't watch '{}': {}", file, e))?;
+        watched.insert(path);
+    }
+    let (sender, mut events) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        if let Ok(event) = event {
+            let _ = sender.send(event);
+        }
+    })?;
+    let dirs: HashSet<&Path> = watched.iter().filter_map(|path| path.parent()).collect();
+    for dir in dirs {
+        watcher.watch(dir, RecursiveMode::NonRecursive)?;
+    }
+
+    loop {
+        print!("\x1b[2J\x1b[H");
+        std::io::stdout().flush()?;
+        let mut child = tokio::process::Command::new(&exe).args(&args).spawn()?;
+        tokio::select! {
+            status = child.wait() => {
+                let status = status?;
+                if !status.success() {
+                    crate::diagnostics::status("watch", format!("The run failed ({}).", status));
+                }
+            }
+            _ = tokio::signal::ctrl_c() => {
+                let _ = child.kill().await;
+                return Ok(());
+            }
+        }
+
+        crate::diagnostics::status("watch", format!("Watching {} for changes; press Ctrl-C to stop.", files.join(", ")));
+        tokio::select! {
+            _ = changed(&mut events, &watched) => {}
+            _ = tokio::signal::ctrl_c() => return Ok(()),
+        }
+    }
+}